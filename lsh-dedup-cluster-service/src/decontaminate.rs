@@ -0,0 +1,238 @@
+use crate::lsh::calculate_hash;
+use crate::util;
+use lsh_dedup_service::dto::{DataFile, DedupConfig, Record};
+use lsh_dedup_service::error::ServiceError;
+use rayon::prelude::*;
+use rusoto_s3::S3Client;
+use rustc_hash::{FxHashSet, FxHasher};
+use serde_json::{json, Value};
+use std::hash::Hasher;
+
+/// Number of tokens of the compared n-grams unless the job sets `ngramSize`
+const DEFAULT_NGRAM_SIZE: usize = 13;
+
+///
+/// The distinct n-grams of the reference records of a decontaminate job, as hashes.
+///
+pub struct NgramSet {
+    size: usize,
+    hashes: FxHashSet<u64>,
+    /// Number of reference records with fewer than `size` tokens, which have no n-grams
+    pub num_short: usize,
+}
+
+impl NgramSet {
+    ///
+    /// Collects the n-grams of `size` tokens of all `records` in parallel.
+    ///
+    pub fn new(records: &[Record], size: usize) -> Self {
+        let (hashes, num_short) = records
+            .par_iter()
+            .fold(
+                || (FxHashSet::default(), 0),
+                |(mut hashes, mut num_short), record| {
+                    let ngrams = ngram_hashes(&record.text, size);
+                    num_short += ngrams.is_empty() as usize;
+                    hashes.extend(ngrams);
+                    (hashes, num_short)
+                },
+            )
+            .reduce(
+                || (FxHashSet::default(), 0),
+                |(mut hashes, num_short), (other, other_short)| {
+                    hashes.extend(other);
+                    (hashes, num_short + other_short)
+                },
+            );
+        NgramSet {
+            size,
+            hashes,
+            num_short,
+        }
+    }
+
+    /// Number of distinct n-grams.
+    pub fn len(&self) -> usize {
+        self.hashes.len()
+    }
+
+    /// Number of distinct n-grams of `text` found in the set.
+    pub fn count_shared(&self, text: &str) -> usize {
+        let mut shared: Vec<u64> = ngram_hashes(text, self.size)
+            .into_iter()
+            .filter(|hash| self.hashes.contains(hash))
+            .collect();
+        shared.sort_unstable();
+        shared.dedup();
+        shared.len()
+    }
+}
+
+/// Whether a record sharing `shared` distinct n-grams with the reference is contaminated.
+pub fn is_contaminated(shared: usize, max_shared: usize) -> bool {
+    shared > max_shared
+}
+
+///
+/// Runs a decontaminate job: counts the n-grams every record of `data` shares with the
+/// records of `reference`, and flags or drops the records sharing more than
+/// `max_shared_ngrams` of them.
+///
+pub async fn decontaminate(client: &S3Client, config: DedupConfig) -> Result<Value, ServiceError> {
+    let Some(reference_source) = &config.reference else {
+        return Err(ServiceError::bad_request(
+            "join and decontaminate jobs require a reference",
+        ));
+    };
+    let corpus = util::load_corpus(client, &config, &config.data).await?;
+    let reference = util::load_corpus(client, &config, reference_source).await?;
+    let all_inputs: Vec<DataFile> = corpus
+        .sources
+        .iter()
+        .chain(&reference.sources)
+        .cloned()
+        .collect();
+    let output = util::resolve_output_file(&config, &all_inputs)?;
+    let start = std::time::Instant::now();
+    let ngrams = NgramSet::new(
+        &reference.records,
+        config.ngram_size.unwrap_or(DEFAULT_NGRAM_SIZE),
+    );
+    let shared: Vec<usize> = corpus
+        .records
+        .par_iter()
+        .map(|record| ngrams.count_shared(&record.text))
+        .collect();
+    let max_shared = config.max_shared_ngrams.unwrap_or(0);
+    println!(
+        "Decontamination completed in {:.4} secs",
+        (std::time::Instant::now() - start).as_secs_f64()
+    );
+    let output_data = util::push_contamination_file(
+        client,
+        &output,
+        &corpus,
+        &shared,
+        max_shared,
+        config.contamination_policy,
+    )
+    .await?;
+    let rejected_data = util::push_rejected_rows(client, &output, &corpus, "rejected").await?;
+    let reference_rejected_data =
+        util::push_rejected_rows(client, &output, &reference, "reference.rejected").await?;
+    Ok(json!({
+        "taskId": config.task_id,
+        "data": output_data,
+        "duplicateIds": corpus.duplicate_ids + reference.duplicate_ids,
+        "rows": {
+            "total": corpus.num_rows,
+            "rejected": corpus.rejected.len(),
+            "malformedEncoding": corpus.malformed_encoding,
+            "contaminated": shared.iter().filter(|&&count| is_contaminated(count, max_shared)).count(),
+        },
+        "referenceRows": {
+            "total": reference.num_rows,
+            "rejected": reference.rejected.len(),
+            "malformedEncoding": reference.malformed_encoding,
+            "short": ngrams.num_short,
+        },
+        "referenceNgrams": ngrams.len(),
+        "rejectedRows": rejected_data,
+        "referenceRejectedRows": reference_rejected_data,
+    }))
+}
+
+///
+/// Hashes the n-grams of `size` tokens of a text, in order. Texts are lowercased and split
+/// into tokens at every character that isn't alphanumeric, so punctuation and whitespace
+/// differences don't hide an overlap.
+///
+fn ngram_hashes(text: &str, size: usize) -> Vec<u64> {
+    let tokens: Vec<u64> = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|token| !token.is_empty())
+        .map(|token| calculate_hash(&token.to_lowercase()))
+        .collect();
+    tokens
+        .windows(size)
+        .map(|ngram| {
+            let mut hasher = FxHasher::default();
+            for &token in ngram {
+                hasher.write_u64(token);
+            }
+            hasher.finish()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records(texts: &[&str]) -> Vec<Record> {
+        texts
+            .iter()
+            .enumerate()
+            .map(|(idx, text)| Record {
+                id: idx.to_string(),
+                text: text.to_string(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn texts_shorter_than_n_have_no_ngrams() {
+        assert!(ngram_hashes("", 3).is_empty());
+        assert!(ngram_hashes("two words", 3).is_empty());
+        assert_eq!(ngram_hashes("just three words", 3).len(), 1);
+        let ngrams = NgramSet::new(&records(&["two words", "", "now three words"]), 3);
+        assert_eq!(ngrams.num_short, 2);
+        assert_eq!(ngrams.len(), 1);
+        assert_eq!(ngrams.count_shared("two words"), 0);
+    }
+
+    #[test]
+    fn ngrams_overlap() {
+        // Every token starts an n-gram, except the last n - 1.
+        let hashes = ngram_hashes("a b c d e", 3);
+        assert_eq!(hashes.len(), 3);
+        assert_eq!(
+            hashes,
+            [
+                ngram_hashes("a b c", 3)[0],
+                ngram_hashes("b c d", 3)[0],
+                ngram_hashes("c d e", 3)[0],
+            ]
+        );
+        // Case, punctuation and whitespace don't matter.
+        assert_eq!(ngram_hashes("A, b;\tC d... E!", 3), hashes);
+        assert_ne!(ngram_hashes("a c b d e", 3)[0], hashes[0]);
+    }
+
+    #[test]
+    fn shared_ngrams_are_counted_once() {
+        let ngrams = NgramSet::new(&records(&["a b c d e", "c d e f"]), 3);
+        assert_eq!(ngrams.len(), 4);
+        assert_eq!(ngrams.count_shared("x a b c y"), 1);
+        assert_eq!(ngrams.count_shared("a b c d e f"), 4);
+        // Repeated n-grams of the record count once.
+        assert_eq!(ngrams.count_shared("a b c a b c"), 1);
+    }
+
+    #[test]
+    fn records_sharing_more_than_the_maximum_are_contaminated() {
+        let ngrams = NgramSet::new(
+            &records(&["the quick brown fox jumps over the lazy dog"]),
+            3,
+        );
+        let shared = ngrams.count_shared("a quick brown fox jumps high");
+        assert_eq!(shared, 2);
+        assert!(is_contaminated(shared, 0));
+        assert!(is_contaminated(shared, 1));
+        assert!(!is_contaminated(shared, 2));
+        assert!(!is_contaminated(
+            ngrams.count_shared("an unrelated text"),
+            0
+        ));
+    }
+}
@@ -1,107 +1,147 @@
 use crate::lsh::MinHashLSH;
-use rayon::prelude::*;
-use std::collections::{HashMap, HashSet};
+use lsh_dedup_service::dto::BucketPolicy;
+use std::collections::HashMap;
 
 ///
-/// Wraps two mappings to determine duplicate clusters based on the results
-/// from querying the MinhashLSH.
+/// Determines duplicate clusters based on the results from querying the MinhashLSH,
+/// using a union-find forest over the record indices of the LSH.
 ///
-pub struct DeduplicationTable<'a> {
-    /// Mapping of duplicate group id to record ids
-    duplicate_groups: HashMap<usize, HashSet<&'a str>>,
-    /// Reverse lookup to identify the group id of a record id
-    doc_lookup: HashMap<&'a str, usize>,
+pub struct DeduplicationTable {
+    /// Parent of every record in the forest, roots are their own parent
+    parents: Vec<u32>,
+    /// Number of records in the cluster of every root
+    sizes: Vec<u32>,
 }
 
-impl<'a> DeduplicationTable<'a> {
+impl DeduplicationTable {
     ///
     /// Constructs an DeduplicationTable instance using an existing MinHashLSH for querying and clustering.
     ///
     /// ## Arguments
     ///
     /// * `lsh` - A MinHashLSH to use for querying record similarity.
+    /// * `first_query` - Index of the first record to query. Earlier records (from a base
+    ///   index) are only clustered through their similarity to later ones.
     /// * `threshold` - The jaccard similarity threshold (inclusive) to filter query results (optional).
+    /// * `max_bucket_size` - Size above which a band bucket is handled by `bucket_policy` (optional).
+    /// * `bucket_policy` - How to find candidates in oversized band buckets.
     ///
-    pub fn new(lsh: MinHashLSH<'a>, threshold: Option<f64>) -> Self {
-        let query_results: Vec<(&str, Vec<&str>)> = lsh
-            .minhash_map
-            .par_iter()
-            .map(|(&id, minhash)| (id, lsh.query(minhash, threshold)))
-            .collect();
-        Self::from_query_results(query_results)
+    pub fn new(
+        lsh: &MinHashLSH,
+        first_query: u32,
+        threshold: Option<f64>,
+        max_bucket_size: Option<usize>,
+        bucket_policy: BucketPolicy,
+    ) -> Self {
+        let query_results = lsh.self_join(first_query, threshold, max_bucket_size, bucket_policy);
+        Self::from_query_results(lsh.len(), query_results)
     }
 
     ///
-    /// Outputs the list of clustered record ids found to be similar enough to form distinct groups.
+    /// Outputs the record indices clustered into distinct groups of similar records.
     ///
-    pub fn grouped_ids(&self) -> Vec<Vec<&'a str>> {
-        self.duplicate_groups
-            .values()
-            .map(|set| set.into_iter().map(|&s| s).collect())
+    pub fn grouped_records(&self) -> Vec<Vec<u32>> {
+        let mut groups: HashMap<u32, Vec<u32>> = HashMap::new();
+        for idx in 0..self.parents.len() as u32 {
+            groups.entry(self.root(idx)).or_default().push(idx);
+        }
+        groups.into_values().collect()
+    }
+
+    ///
+    /// Like `grouped_records`, but replaces every record index of the LSH with the records
+    /// it stands for (`members[idx]`), e.g. the exact copies of a representative.
+    ///
+    pub fn grouped_records_with(&self, members: &[Vec<u32>]) -> Vec<Vec<u32>> {
+        self.grouped_records()
+            .into_iter()
+            .map(|group| {
+                group
+                    .into_iter()
+                    .flat_map(|idx| members[idx as usize].iter().copied())
+                    .collect()
+            })
             .collect()
     }
 
-    fn init() -> Self {
-        Self {
-            duplicate_groups: HashMap::new(),
-            doc_lookup: HashMap::new(),
+    ///
+    /// Assigns a cluster label to every record after the `labels.len()` records labeled by
+    /// earlier jobs. A record clustered with labeled records takes the smallest of their
+    /// labels, and is returned with the earliest record carrying that label. Other clusters
+    /// get fresh labels above all existing ones.
+    ///
+    pub fn label_clusters(&self, labels: &[u32]) -> Vec<(u32, Option<u32>)> {
+        let mut existing: HashMap<u32, (u32, u32)> = HashMap::new();
+        for (idx, &label) in labels.iter().enumerate() {
+            let entry = existing
+                .entry(self.root(idx as u32))
+                .or_insert((label, idx as u32));
+            *entry = (*entry).min((label, idx as u32));
         }
+        let mut next_label = labels.iter().max().map_or(0, |&label| label + 1);
+        let mut fresh: HashMap<u32, u32> = HashMap::new();
+        (labels.len() as u32..self.parents.len() as u32)
+            .map(|idx| {
+                let root = self.root(idx);
+                match existing.get(&root) {
+                    Some(&(label, earliest)) => (label, Some(earliest)),
+                    None => {
+                        let label = *fresh.entry(root).or_insert_with(|| {
+                            next_label += 1;
+                            next_label - 1
+                        });
+                        (label, None)
+                    }
+                }
+            })
+            .collect()
     }
 
-    fn from_query_results(query_results: Vec<(&'a str, Vec<&'a str>)>) -> Self {
-        let mut document_clusters = Self::init();
+    ///
+    /// Clusters `num_records` records given the similar records found for each of them, as
+    /// returned by [`MinHashLSH::self_join`].
+    ///
+    pub fn from_query_results(num_records: usize, query_results: Vec<(u32, Vec<u32>)>) -> Self {
+        let mut document_clusters = Self {
+            parents: (0..num_records as u32).collect(),
+            sizes: vec![1; num_records],
+        };
         for (query_doc_id, similar_documents) in query_results {
-            let cluster_id = document_clusters
-                .check_cluster_id(query_doc_id)
-                .unwrap_or(document_clusters.new_id());
-            let mut current_cluster = HashSet::new();
             for similar_doc_id in similar_documents {
-                if let Some(prev_cluster_id) = document_clusters.check_cluster_id(similar_doc_id) {
-                    if prev_cluster_id != cluster_id {
-                        let reassignment = document_clusters.remove(prev_cluster_id);
-                        current_cluster.extend(reassignment);
-                    }
-                } else {
-                    if !current_cluster.contains(similar_doc_id) {
-                        current_cluster.insert(similar_doc_id);
-                    }
-                }
+                document_clusters.union(query_doc_id, similar_doc_id);
             }
-            document_clusters.update(cluster_id, current_cluster);
         }
         document_clusters
     }
 
-    fn update(&mut self, cluster_id: usize, doc_set: HashSet<&'a str>) {
-        for doc_id in doc_set {
-            self.add(cluster_id, doc_id);
+    fn root(&self, mut doc_id: u32) -> u32 {
+        while self.parents[doc_id as usize] != doc_id {
+            doc_id = self.parents[doc_id as usize];
         }
+        doc_id
     }
 
-    fn add(&mut self, cluster_id: usize, doc_id: &'a str) {
-        self.duplicate_groups
-            .entry(cluster_id)
-            .or_insert_with(HashSet::new)
-            .insert(doc_id);
-        self.doc_lookup.insert(doc_id, cluster_id);
-    }
-
-    fn check_cluster_id(&mut self, doc_id: &'a str) -> Option<usize> {
-        self.doc_lookup.get(doc_id).copied()
-    }
-
-    fn new_id(&self) -> usize {
-        self.duplicate_groups.keys().max().map_or(0, |&v| v + 1)
+    fn find(&mut self, doc_id: u32) -> u32 {
+        let root = self.root(doc_id);
+        let mut current = doc_id;
+        while current != root {
+            current = std::mem::replace(&mut self.parents[current as usize], root);
+        }
+        root
     }
 
-    fn remove(&mut self, cluster_id: usize) -> HashSet<&'a str> {
-        let set = self
-            .duplicate_groups
-            .remove(&cluster_id)
-            .expect(&format!("Cluster {cluster_id} doesn't exist"));
-        for k in set.iter() {
-            self.doc_lookup.remove(k);
+    fn union(&mut self, a: u32, b: u32) {
+        let (a, b) = (self.find(a), self.find(b));
+        if a == b {
+            return;
         }
-        set
+        // Attach the smaller cluster below the larger one to keep the trees shallow.
+        let (small, large) = if self.sizes[a as usize] < self.sizes[b as usize] {
+            (a, b)
+        } else {
+            (b, a)
+        };
+        self.parents[small as usize] = large;
+        self.sizes[large as usize] += self.sizes[small as usize];
     }
 }
@@ -0,0 +1,244 @@
+use crate::forest::LshForest;
+use crate::lsh::LshFamily;
+use rayon::prelude::*;
+
+/// Number of set size partitions unless the job sets `numPartitions`
+pub const DEFAULT_PARTITIONS: usize = 16;
+/// Probability of becoming candidates the prefix length is chosen for, at the lowest jaccard
+/// similarity a record containing the queried one can have within a partition
+const TARGET_RECALL: f64 = 0.9;
+
+///
+/// LSH Ensemble (Zhu et al., 2016) for containment queries over full MinHash signatures.
+///
+/// A record contained in a much larger one has a low jaccard similarity with it, so a single
+/// banding that finds it would return far too many candidates for records of similar sizes.
+/// The records are split into partitions of about the same number of records by their set
+/// size, each indexed by an [`LshForest`]. A query converts the containment threshold into
+/// the lowest jaccard similarity a containing record can have in each partition (given the
+/// largest set size of the partition) and picks the prefix length of the forest keys
+/// accordingly.
+///
+pub struct LshEnsemble {
+    /// Row-major matrix of the signature of every record
+    signatures: Vec<u32>,
+    family: Box<dyn LshFamily>,
+    /// Number of distinct tokens of every record
+    sizes: Vec<u32>,
+    partitions: Vec<Partition>,
+}
+
+struct Partition {
+    /// Largest set size of the partition
+    max_size: u32,
+    /// Index of every record of the forest
+    records: Vec<u32>,
+    forest: LshForest,
+}
+
+impl LshEnsemble {
+    ///
+    /// Partitions the records signed by `family` by their set size (`sizes`) and builds a
+    /// forest over each partition. The signatures must hold every MinHash value unpacked.
+    ///
+    pub fn new(
+        signatures: Vec<u32>,
+        family: Box<dyn LshFamily>,
+        sizes: Vec<u32>,
+        num_partitions: usize,
+    ) -> Self {
+        let words = family.words();
+        let mut order: Vec<u32> = (0..sizes.len() as u32).collect();
+        order.sort_unstable_by_key(|&idx| sizes[idx as usize]);
+        let per_partition = order.len().div_ceil(num_partitions).max(1);
+        let partitions = order
+            .par_chunks(per_partition)
+            .map(|records| {
+                let signatures: Vec<u32> = records
+                    .iter()
+                    .flat_map(|&idx| {
+                        let start = idx as usize * words;
+                        signatures[start..start + words].iter().copied()
+                    })
+                    .collect();
+                Partition {
+                    max_size: sizes[records[records.len() - 1] as usize],
+                    records: records.to_vec(),
+                    forest: LshForest::new(signatures, family.params().into_family()),
+                }
+            })
+            .collect();
+        LshEnsemble {
+            signatures,
+            family,
+            sizes,
+            partitions,
+        }
+    }
+
+    /// The signature of the record at index `idx`.
+    fn signature(&self, idx: u32) -> &[u32] {
+        let words = self.family.words();
+        let start = idx as usize * words;
+        &self.signatures[start..start + words]
+    }
+
+    ///
+    /// Finds the records that contain at least `threshold` of the tokens of every
+    /// record, among the records at least as large. The result for a record holds the record
+    /// itself followed by those records, so every pair is reported once.
+    ///
+    pub fn self_join(&self, threshold: f64) -> Vec<(u32, Vec<u32>)> {
+        (0..self.sizes.len() as u32)
+            .into_par_iter()
+            .map(|idx| {
+                let size = self.sizes[idx as usize];
+                // Containment in an empty record is undefined.
+                if size == 0 {
+                    return (idx, vec![idx]);
+                }
+                let signature = self.signature(idx);
+                let mut similar = Vec::new();
+                for partition in self.partitions.iter().filter(|p| p.max_size >= size) {
+                    let overlap = threshold * size as f64;
+                    let jaccard = overlap / (partition.max_size as f64 + size as f64 - overlap);
+                    let prefix_len = prefix_length(
+                        jaccard,
+                        partition.forest.depth(),
+                        partition.forest.num_trees(),
+                    );
+                    similar.extend(
+                        partition
+                            .forest
+                            .candidates(signature, prefix_len)
+                            .into_iter()
+                            .map(|doc| partition.records[doc as usize])
+                            .filter(|&other| (self.sizes[other as usize], other) > (size, idx)),
+                    );
+                }
+                similar.sort_unstable();
+                similar.dedup();
+                similar.retain(|&other| {
+                    let jaccard = self.family.similarity(signature, self.signature(other));
+                    let (size, other_size) = (size as f64, self.sizes[other as usize] as f64);
+                    let intersection = jaccard * (size + other_size) / (1.0 + jaccard);
+                    intersection / size >= threshold
+                });
+                similar.push(idx);
+                (idx, similar)
+            })
+            .collect()
+    }
+}
+
+/// The longest prefix of `depth` values with which a record of the given jaccard similarity
+/// becomes a candidate in one of `num_trees` trees with probability [`TARGET_RECALL`].
+fn prefix_length(jaccard: f64, depth: usize, num_trees: usize) -> usize {
+    (1..=depth)
+        .rev()
+        .find(|&len| 1.0 - (1.0 - jaccard.powi(len as i32)).powi(num_trees as i32) >= TARGET_RECALL)
+        .unwrap_or(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lsh;
+    use lsh_dedup_service::dto::{SignatureScheme, TokenWeighting};
+
+    /// Full MinHash signatures of 16 values in 4 bands (keys of 4 values)
+    fn family() -> Box<dyn LshFamily> {
+        lsh::new_family(SignatureScheme::MinHash, 16, 4, 32, TokenWeighting::None)
+    }
+
+    /// A signature sharing the values at `shared` with `0, 1, ..., 15`
+    fn signature(base: u32, shared: &[u32]) -> Vec<u32> {
+        (0..16)
+            .map(|pos| {
+                if shared.contains(&pos) {
+                    pos
+                } else {
+                    base + pos
+                }
+            })
+            .collect()
+    }
+
+    /// A 10 token snippet, a 100 token document containing it (jaccard estimate 2/16), a 100
+    /// token document sharing a single value with it and an unrelated 10 token snippet
+    fn ensemble(num_partitions: usize) -> LshEnsemble {
+        let signatures = [
+            (0..16).collect(),
+            signature(100, &[0, 1]),
+            signature(200, &[0]),
+            signature(300, &[]),
+        ];
+        LshEnsemble::new(
+            signatures.concat(),
+            family(),
+            vec![10, 100, 100, 10],
+            num_partitions,
+        )
+    }
+
+    #[test]
+    fn contained_record_is_joined_despite_a_low_jaccard_similarity() {
+        let snippet: Vec<u32> = (0..16).collect();
+        assert_eq!(
+            family().similarity(&snippet, &signature(100, &[0, 1])),
+            0.125
+        );
+        for num_partitions in [1, 2, 3, 4, 8] {
+            // The estimated containment of the snippet is 1.2 in the first document and 0.65
+            // in the second.
+            let joined = ensemble(num_partitions).self_join(0.8);
+            assert_eq!(
+                joined,
+                [(0, vec![1, 0]), (1, vec![1]), (2, vec![2]), (3, vec![3])],
+                "{num_partitions} partitions"
+            );
+            let joined = ensemble(num_partitions).self_join(0.6);
+            assert_eq!(joined[0], (0, vec![1, 2, 0]), "{num_partitions} partitions");
+        }
+    }
+
+    #[test]
+    fn records_of_equal_size_across_partitions_are_joined_once() {
+        let signatures = [
+            signature(100, &[]),
+            signature(100, &[]),
+            signature(200, &[]),
+        ];
+        for num_partitions in [1, 2, 3] {
+            let ensemble =
+                LshEnsemble::new(signatures.concat(), family(), vec![10; 3], num_partitions);
+            assert_eq!(
+                ensemble.self_join(0.9),
+                [(0, vec![1, 0]), (1, vec![1]), (2, vec![2])],
+                "{num_partitions} partitions"
+            );
+        }
+    }
+
+    #[test]
+    fn partitions_hold_records_of_similar_sizes() {
+        let partitions: Vec<(u32, Vec<u32>)> = ensemble(2)
+            .partitions
+            .iter()
+            .map(|partition| (partition.max_size, partition.records.clone()))
+            .collect();
+        assert_eq!(partitions, [(10, vec![0, 3]), (100, vec![1, 2])]);
+        // More partitions than records leave one record per partition.
+        assert_eq!(ensemble(8).partitions.len(), 4);
+    }
+
+    #[test]
+    fn prefix_length_reaches_the_target_recall() {
+        assert_eq!(prefix_length(1.0, 4, 4), 4);
+        assert_eq!(prefix_length(0.9, 4, 4), 4);
+        assert_eq!(prefix_length(0.7, 4, 4), 2);
+        assert_eq!(prefix_length(0.5, 4, 4), 1);
+        // Low similarities fall back to the first value of every key.
+        assert_eq!(prefix_length(0.0, 4, 4), 1);
+    }
+}
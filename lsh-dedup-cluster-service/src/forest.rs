@@ -0,0 +1,200 @@
+use crate::lsh::LshFamily;
+use rayon::prelude::*;
+
+///
+/// LSH Forest (Bawa et al., 2005) over full MinHash signatures, answering top-k queries
+/// instead of threshold queries.
+///
+/// Every band of the signature is the key of one prefix tree, stored as the record indices
+/// sorted by their keys. A query collects the records sharing the longest key prefixes with
+/// it across all trees, shortening the prefixes until at least `k` candidates are found, and
+/// ranks the candidates by their estimated similarity.
+///
+pub struct LshForest {
+    /// Row-major matrix of the unpacked signatures
+    signatures: Vec<u32>,
+    family: Box<dyn LshFamily>,
+    /// Number of signature values per key
+    depth: usize,
+    /// Record indices sorted by the key of every tree
+    trees: Vec<Vec<u32>>,
+}
+
+impl LshForest {
+    ///
+    /// Builds a tree per band of `family` over the signatures, which must hold every
+    /// MinHash value unpacked (32 signature bits).
+    ///
+    pub fn new(signatures: Vec<u32>, family: Box<dyn LshFamily>) -> Self {
+        let num_records = signatures.len() / family.words();
+        let depth = family.words() / family.num_bands();
+        let mut forest = LshForest {
+            signatures,
+            family,
+            depth,
+            trees: Vec::new(),
+        };
+        forest.trees = (0..forest.family.num_bands())
+            .into_par_iter()
+            .map(|tree| {
+                let mut sorted: Vec<u32> = (0..num_records as u32).collect();
+                sorted.sort_unstable_by_key(|&idx| forest.key(tree, idx));
+                sorted
+            })
+            .collect();
+        forest
+    }
+
+    /// Number of records in the forest.
+    pub fn len(&self) -> usize {
+        self.signatures.len() / self.family.words()
+    }
+
+    /// The stored signature of the record at index `idx`.
+    pub fn signature(&self, idx: u32) -> &[u32] {
+        let words = self.family.words();
+        let start = idx as usize * words;
+        &self.signatures[start..start + words]
+    }
+
+    /// The key of the record at index `idx` in `tree`.
+    fn key(&self, tree: usize, idx: u32) -> &[u32] {
+        &self.signature(idx)[tree * self.depth..(tree + 1) * self.depth]
+    }
+
+    ///
+    /// Finds the `k` records most similar to `signature`, most similar first, leaving out the
+    /// record at index `exclude` (the queried record itself, if it is in the forest). Fewer
+    /// are returned if fewer records share the first value of a key with it.
+    ///
+    pub fn query(&self, signature: &[u32], k: usize, exclude: Option<u32>) -> Vec<(u32, f64)> {
+        let mut candidates: Vec<u32> = Vec::new();
+        for prefix_len in (1..=self.depth).rev() {
+            for tree in 0..self.trees.len() {
+                candidates.extend_from_slice(self.prefix_matches(tree, signature, prefix_len));
+            }
+            candidates.sort_unstable();
+            candidates.dedup();
+            if let Some(exclude) = exclude {
+                candidates.retain(|&idx| idx != exclude);
+            }
+            if candidates.len() >= k {
+                break;
+            }
+        }
+        let mut nearest: Vec<(u32, f64)> = candidates
+            .into_iter()
+            .map(|idx| (idx, self.family.similarity(signature, self.signature(idx))))
+            .collect();
+        nearest.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+        nearest.truncate(k);
+        nearest
+    }
+
+    ///
+    /// Finds the records sharing the first `prefix_len` values of some key with `signature`,
+    /// in no particular order and possibly repeated.
+    ///
+    pub fn candidates(&self, signature: &[u32], prefix_len: usize) -> Vec<u32> {
+        (0..self.trees.len())
+            .flat_map(|tree| self.prefix_matches(tree, signature, prefix_len))
+            .copied()
+            .collect()
+    }
+
+    /// Number of signature values per key, the longest usable prefix.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Number of trees, one per band.
+    pub fn num_trees(&self) -> usize {
+        self.trees.len()
+    }
+
+    /// The records whose key in `tree` starts with the same `prefix_len` values as that of
+    /// `signature`.
+    fn prefix_matches(&self, tree: usize, signature: &[u32], prefix_len: usize) -> &[u32] {
+        let sorted = &self.trees[tree];
+        let prefix = &signature[tree * self.depth..][..prefix_len];
+        let start = sorted.partition_point(|&idx| &self.key(tree, idx)[..prefix_len] < prefix);
+        let len =
+            sorted[start..].partition_point(|&idx| &self.key(tree, idx)[..prefix_len] == prefix);
+        &sorted[start..start + len]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lsh;
+    use lsh_dedup_service::dto::{SignatureScheme, TokenWeighting};
+
+    /// The query signature, in two keys of 4 values
+    const QUERY: [u32; 8] = [1, 2, 3, 4, 5, 6, 7, 8];
+
+    /// A forest over the query itself and records sharing shorter and shorter prefixes with it
+    fn forest() -> LshForest {
+        let signatures = [
+            QUERY,
+            // Identical to the query
+            [1, 2, 3, 4, 5, 6, 7, 8],
+            // Shares 3 values of the first key and 2 of the second, similarity 0.625
+            [1, 2, 3, 9, 5, 6, 9, 9],
+            // Shares a single value of the first key only, but similarity 0.75
+            [1, 9, 3, 4, 9, 6, 7, 8],
+            // Shares no first value of a key
+            [9, 2, 3, 4, 9, 6, 7, 8],
+        ];
+        let family = lsh::new_family(SignatureScheme::MinHash, 8, 2, 32, TokenWeighting::None);
+        LshForest::new(signatures.concat(), family)
+    }
+
+    fn ids(nearest: &[(u32, f64)]) -> Vec<u32> {
+        nearest.iter().map(|&(idx, _)| idx).collect()
+    }
+
+    #[test]
+    fn identical_record_comes_first() {
+        let forest = forest();
+        let nearest = forest.query(&QUERY, 10, Some(0));
+        assert_eq!(nearest[0], (1, 1.0));
+        // The query itself is excluded, unless it isn't in the forest.
+        assert_eq!(ids(&nearest), [1, 3, 2]);
+        assert_eq!(ids(&forest.query(&QUERY, 10, None)), [0, 1, 3, 2]);
+    }
+
+    #[test]
+    fn k_bounds_the_result() {
+        let forest = forest();
+        for k in 1..=3 {
+            assert_eq!(forest.query(&QUERY, k, Some(0)).len(), k);
+        }
+        // Record 4 doesn't share the first value of any key.
+        assert_eq!(forest.query(&QUERY, 4, Some(0)).len(), 3);
+    }
+
+    #[test]
+    fn descent_stops_at_the_longest_prefix_with_k_candidates() {
+        let forest = forest();
+        assert_eq!(ids(&forest.query(&QUERY, 1, Some(0))), [1]);
+        // Record 2 is found with 3 values, before the more similar record 3 is reached.
+        assert_eq!(ids(&forest.query(&QUERY, 2, Some(0))), [1, 2]);
+        assert_eq!(ids(&forest.query(&QUERY, 3, Some(0))), [1, 3, 2]);
+    }
+
+    #[test]
+    fn candidates_share_a_key_prefix() {
+        let forest = forest();
+        let candidates = |prefix_len| {
+            let mut candidates = forest.candidates(&QUERY, prefix_len);
+            candidates.sort_unstable();
+            candidates.dedup();
+            candidates
+        };
+        assert_eq!(candidates(4), [0, 1]);
+        assert_eq!(candidates(3), [0, 1, 2]);
+        assert_eq!(candidates(2), [0, 1, 2]);
+        assert_eq!(candidates(1), [0, 1, 2, 3]);
+    }
+}
@@ -0,0 +1,158 @@
+use crate::lsh::{band_range, calculate_band_hash, FamilyParams, LshFamily};
+use rand::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::f64::consts::TAU;
+
+/// Random-hyperplane LSH (Charikar, 2002) over embedding vectors.
+///
+/// Every signature bit tells on which side of a random hyperplane through the origin a
+/// vector lies, so two vectors agree on a bit with probability `1 - angle / π`. The bits are
+/// split into contiguous bands covering every bit, while candidates are verified by the exact cosine similarity
+/// of the normalized vectors, which are stored as the signature.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RandomHyperplanes {
+    dimensions: usize,
+    /// Row-major `num_planes × dimensions` matrix of the plane normals
+    normals: Vec<f32>,
+    num_bands: usize,
+}
+
+impl RandomHyperplanes {
+    pub fn new(dimensions: usize, num_planes: usize, num_bands: usize) -> Self {
+        let mut rng = StdRng::from_entropy();
+        // Gaussian normals (Box-Muller) make the planes uniformly distributed in direction.
+        let normals = (0..num_planes * dimensions)
+            .map(|_| {
+                let (u, v): (f64, f64) = (1.0 - rng.gen::<f64>(), rng.gen());
+                ((-2.0 * u.ln()).sqrt() * (TAU * v).cos()) as f32
+            })
+            .collect();
+        RandomHyperplanes {
+            dimensions,
+            normals,
+            num_bands,
+        }
+    }
+
+    /// Number of random hyperplanes, i.e. of bits banded per record.
+    pub fn num_planes(&self) -> usize {
+        self.normals.len() / self.dimensions
+    }
+
+    /// Whether there are 1 to `num_planes` bands of planes with `dimensions` coordinates.
+    pub fn is_valid(&self) -> bool {
+        self.dimensions > 0
+            && self.normals.len().is_multiple_of(self.dimensions)
+            && (1..=self.normals.len() / self.dimensions).contains(&self.num_bands)
+    }
+
+    /// Writes the normalized `vector` as the stored signature and the hashes of its banded
+    /// sign bits.
+    pub fn sign(
+        &self,
+        vector: &[f32],
+        scratch: &mut Vec<u32>,
+        signature: &mut [u32],
+        bands: &mut [u64],
+    ) {
+        scratch.clear();
+        scratch.extend(self.normals.chunks_exact(self.dimensions).map(|normal| {
+            let dot: f32 = normal.iter().zip(vector).map(|(a, b)| a * b).sum();
+            (dot >= 0.0) as u32
+        }));
+        for (band, band_hash) in bands.iter_mut().enumerate() {
+            *band_hash =
+                calculate_band_hash(&scratch[band_range(band, self.num_bands, scratch.len())]);
+        }
+        let norm = vector
+            .iter()
+            .map(|x| x * x)
+            .sum::<f32>()
+            .sqrt()
+            .max(f32::MIN_POSITIVE);
+        for (value, x) in signature.iter_mut().zip(vector) {
+            *value = (x / norm).to_bits();
+        }
+    }
+}
+
+impl LshFamily for RandomHyperplanes {
+    fn words(&self) -> usize {
+        self.dimensions
+    }
+
+    fn num_bands(&self) -> usize {
+        self.num_bands
+    }
+
+    /// Cosine similarity of the stored normalized vectors.
+    fn similarity(&self, signature: &[u32], other: &[u32]) -> f64 {
+        signature
+            .iter()
+            .zip(other)
+            .map(|(&a, &b)| f32::from_bits(a) as f64 * f32::from_bits(b) as f64)
+            .sum()
+    }
+
+    fn params(&self) -> FamilyParams {
+        FamilyParams::RandomHyperplanes(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sign(family: &RandomHyperplanes, vector: &[f32]) -> (Vec<u32>, Vec<u64>) {
+        let mut signature = vec![0; family.words()];
+        let mut bands = vec![0; family.num_bands()];
+        family.sign(vector, &mut Vec::new(), &mut signature, &mut bands);
+        (signature, bands)
+    }
+
+    #[test]
+    fn identical_vectors_give_identical_signatures() {
+        let family = RandomHyperplanes::new(4, 16, 4);
+        let signed = sign(&family, &[3.0, -4.0, 0.5, 2.0]);
+        assert_eq!(sign(&family, &[3.0, -4.0, 0.5, 2.0]), signed);
+        let similarity = family.similarity(&signed.0, &signed.0);
+        assert!((similarity - 1.0).abs() < 1e-6, "{similarity}");
+    }
+
+    #[test]
+    fn similarity_is_the_cosine() {
+        let family = RandomHyperplanes::new(3, 16, 4);
+        let similarity =
+            |a: &[f32], b: &[f32]| family.similarity(&sign(&family, a).0, &sign(&family, b).0);
+        assert!(similarity(&[1.0, 0.0, 0.0], &[0.0, 2.0, 0.0]).abs() < 1e-6);
+        let diagonal = similarity(&[1.0, 0.0, 0.0], &[1.0, 1.0, 0.0]);
+        assert!((diagonal - 0.5f64.sqrt()).abs() < 1e-6, "{diagonal}");
+        let opposite = similarity(&[1.0, 2.0, 3.0], &[-2.0, -4.0, -6.0]);
+        assert!((opposite + 1.0).abs() < 1e-6, "{opposite}");
+    }
+
+    #[test]
+    fn bands_cover_every_plane_once() {
+        // The normals are the axes, so every coordinate decides the bit of its plane.
+        let dimensions = 10;
+        let normals: Vec<f32> = (0..dimensions * dimensions)
+            .map(|idx| (idx % (dimensions + 1) == 0) as u32 as f32)
+            .collect();
+        for num_bands in 1..=dimensions {
+            let family = RandomHyperplanes {
+                dimensions,
+                normals: normals.clone(),
+                num_bands,
+            };
+            let (_, bands) = sign(&family, &vec![1.0; dimensions]);
+            for plane in 0..dimensions {
+                let mut vector = vec![1.0; dimensions];
+                vector[plane] = -1.0;
+                let (_, flipped) = sign(&family, &vector);
+                let differing = bands.iter().zip(&flipped).filter(|(a, b)| a != b).count();
+                assert_eq!(differing, 1, "plane {plane}, {num_bands} bands");
+            }
+        }
+    }
+}
@@ -0,0 +1,129 @@
+use crate::hyperplane::RandomHyperplanes;
+use crate::lsh::{self, MinHashLSH};
+use crate::prefilter::{self, PrunedTokens, Tokenizer};
+use crate::util::{self, Corpus};
+use lsh_dedup_service::dto::{DataFile, DedupConfig};
+use lsh_dedup_service::error::ServiceError;
+use rusoto_s3::S3Client;
+use serde_json::{json, Value};
+
+///
+/// Runs a join job: indexes the records of `reference`, queries the index with every record
+/// of `data` and writes the pairs of similar records. Neither dataset is clustered.
+///
+pub async fn join(client: &S3Client, config: DedupConfig) -> Result<Value, ServiceError> {
+    let Some(reference_source) = &config.reference else {
+        return Err(ServiceError::bad_request("join jobs require a reference"));
+    };
+    let corpus = util::load_corpus(client, &config, &config.data).await?;
+    let reference = util::load_corpus(client, &config, reference_source).await?;
+    let all_inputs: Vec<DataFile> = corpus
+        .sources
+        .iter()
+        .chain(&reference.sources)
+        .cloned()
+        .collect();
+    let output = util::resolve_output_file(&config, &all_inputs)?;
+    let (num_perm, num_bands) = config.lsh_params()?;
+    let threshold = config.threshold()?;
+    let start = std::time::Instant::now();
+    let (pruned, queried, short, indexed, lsh, query) = if config.embedding_column.is_some() {
+        let dimensions = reference.embedding_dimensions()?;
+        if corpus.embedding_dimensions()? != dimensions {
+            return Err(ServiceError::bad_request(format!(
+                "data embeddings must have {dimensions} dimensions like the reference"
+            )));
+        }
+        let family = RandomHyperplanes::new(dimensions, num_perm, num_bands);
+        let query = lsh::sign_vectors(&corpus.embeddings, &family, Default::default());
+        let lsh = MinHashLSH::from_vectors(&reference.embeddings, family, Default::default());
+        let queried = (0..corpus.records.len() as u32).collect();
+        let indexed = (0..reference.records.len() as u32).collect();
+        (
+            PrunedTokens::default(),
+            queried,
+            Vec::new(),
+            indexed,
+            lsh,
+            query,
+        )
+    } else {
+        let pruned = PrunedTokens::new(&reference.records, config.max_document_frequency);
+        let min_tokens = config.min_tokens.unwrap_or(1);
+        let (queried, short) = prefilter::split_short_records(&corpus.records, &pruned, min_tokens);
+        let (indexed, _) = prefilter::split_short_records(&reference.records, &pruned, min_tokens);
+        let query_texts = texts(&corpus, &queried);
+        let reference_texts = texts(&reference, &indexed);
+        let family = lsh::new_family(
+            config.signature_scheme,
+            num_perm,
+            num_bands,
+            config.signature_bits.unwrap_or(32),
+            config.token_weighting,
+        );
+        let tokenizer = Tokenizer::new(&reference.texts(), pruned, config.token_weighting);
+        let query = lsh::sign_texts(
+            &query_texts,
+            &tokenizer,
+            family.as_ref(),
+            Default::default(),
+        );
+        let lsh = MinHashLSH::new(&reference_texts, &tokenizer, family, Default::default());
+        (tokenizer.pruned, queried, short, indexed, lsh, query)
+    };
+    let matches = lsh.join(
+        &query.0,
+        &query.1,
+        threshold,
+        config.max_bucket_size,
+        config.bucket_policy,
+    );
+    println!(
+        "Join completed in {:.4} secs",
+        (std::time::Instant::now() - start).as_secs_f64()
+    );
+    let ids: Vec<&str> = indexed
+        .iter()
+        .map(|&idx| reference.records[idx as usize].id.as_str())
+        .collect();
+    let buckets = util::describe_buckets(&lsh, &ids, config.max_bucket_size);
+    let output_data = util::push_pair_file(
+        client, &output, &corpus, &queried, &reference, &indexed, &matches,
+    )
+    .await?;
+    let rejected_data = util::push_rejected_rows(client, &output, &corpus, "rejected").await?;
+    let reference_rejected_data =
+        util::push_rejected_rows(client, &output, &reference, "reference.rejected").await?;
+    let short_data = util::push_short_records(client, &output, &corpus, &pruned, &short).await?;
+    Ok(json!({
+        "taskId": config.task_id,
+        "data": output_data,
+        "duplicateIds": corpus.duplicate_ids + reference.duplicate_ids,
+        "rows": {
+            "total": corpus.num_rows,
+            "rejected": corpus.rejected.len(),
+            "malformedEncoding": corpus.malformed_encoding,
+            "short": short.len(),
+            "matched": matches.iter().filter(|similar| !similar.is_empty()).count(),
+        },
+        "referenceRows": {
+            "total": reference.num_rows,
+            "rejected": reference.rejected.len(),
+            "malformedEncoding": reference.malformed_encoding,
+            "short": reference.records.len() - indexed.len(),
+        },
+        "pairs": matches.iter().map(Vec::len).sum::<usize>(),
+        "rejectedRows": rejected_data,
+        "referenceRejectedRows": reference_rejected_data,
+        "shortRecords": short_data,
+        "diagnostics": { "buckets": buckets, "prunedTokens": pruned.describe() },
+    }))
+}
+
+/// The texts of the records at `indices` of the corpus.
+fn texts<'a>(corpus: &'a Corpus, indices: &[u32]) -> Vec<&'a str> {
+    indices
+        .iter()
+        .map(|&idx| corpus.records[idx as usize].text.as_str())
+        .collect()
+}
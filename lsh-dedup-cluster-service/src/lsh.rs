@@ -1,129 +1,793 @@
-use lsh_dedup_service::dto::Record;
+use crate::hyperplane::RandomHyperplanes;
+use crate::prefilter::Tokenizer;
+use crate::simhash::SimHash;
+use lsh_dedup_service::dto::{BucketPolicy, SignatureScheme, TokenWeighting};
 use rand::prelude::*;
 use rayon::prelude::*;
 use rustc_hash::FxHasher;
-use std::collections::{HashMap, HashSet};
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::HashMap;
 use std::hash::{Hash, Hasher};
+use std::ops::Range;
 
-#[derive(Clone, Debug)]
+/// A family of locality-sensitive hash functions: how records are split into bands and
+/// compared. Signatures are stored as a fixed number of `u32` words per record.
+pub trait LshFamily: Send + Sync {
+    /// Number of `u32` words of every stored signature.
+    fn words(&self) -> usize;
+
+    /// Number of band hashes of every record.
+    fn num_bands(&self) -> usize;
+
+    /// Estimates the similarity (0-1) of the records with the given stored signatures.
+    fn similarity(&self, signature: &[u32], other: &[u32]) -> f64;
+
+    /// The random parameters of the family, which records signed later on must share.
+    fn params(&self) -> FamilyParams;
+}
+
+/// The parameters of every [`LshFamily`], as persisted in index snapshots.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum FamilyParams {
+    MinHash(MinHash),
+    SimHash(SimHash),
+    RandomHyperplanes(RandomHyperplanes),
+}
+
+impl FamilyParams {
+    /// Recreates the family.
+    pub fn into_family(self) -> Box<dyn LshFamily> {
+        match self {
+            FamilyParams::MinHash(minhash) => Box::new(minhash),
+            FamilyParams::SimHash(simhash) => Box::new(simhash),
+            FamilyParams::RandomHyperplanes(hyperplanes) => Box::new(hyperplanes),
+        }
+    }
+
+    /// Whether the parameters, e.g. read back from an index snapshot, can sign and compare
+    /// records.
+    pub fn is_valid(&self) -> bool {
+        match self {
+            FamilyParams::MinHash(minhash) => minhash.is_valid(),
+            FamilyParams::SimHash(simhash) => simhash.is_valid(),
+            FamilyParams::RandomHyperplanes(hyperplanes) => hyperplanes.is_valid(),
+        }
+    }
+
+    /// The first of the job options `numPerm`, `numBands`, `signatureScheme` and
+    /// `signatureBits` that differs from the ones the family was created with, if any.
+    /// Options that don't apply to the family are left out.
+    pub fn mismatched_option(
+        &self,
+        num_perm: usize,
+        num_bands: usize,
+        scheme: SignatureScheme,
+        signature_bits: Option<u32>,
+    ) -> Option<&'static str> {
+        let (family_perm, family_bands, family_scheme, family_bits) = match self {
+            FamilyParams::MinHash(minhash) => (
+                Some(minhash.num_perm),
+                minhash.num_bands,
+                Some(minhash.scheme),
+                Some(minhash.packing.bits),
+            ),
+            FamilyParams::SimHash(simhash) => {
+                (None, simhash.num_bands(), Some(simhash.scheme()), None)
+            }
+            FamilyParams::RandomHyperplanes(hyperplanes) => (
+                Some(hyperplanes.num_planes()),
+                hyperplanes.num_bands(),
+                None,
+                None,
+            ),
+        };
+        if family_perm.is_some_and(|family_perm| family_perm != num_perm) {
+            Some("numPerm")
+        } else if family_bands != num_bands {
+            Some("numBands")
+        } else if family_scheme.is_some_and(|family_scheme| family_scheme != scheme) {
+            Some("signatureScheme")
+        } else if family_bits.is_some_and(|bits| bits != signature_bits.unwrap_or(32)) {
+            Some("signatureBits")
+        } else {
+            None
+        }
+    }
+
+    /// Recreates the family if it signs records by their tokens.
+    pub fn into_token_family(self) -> Option<Box<dyn TokenLshFamily>> {
+        match self {
+            FamilyParams::MinHash(minhash) => Some(Box::new(minhash)),
+            FamilyParams::SimHash(simhash) => Some(Box::new(simhash)),
+            FamilyParams::RandomHyperplanes(_) => None,
+        }
+    }
+}
+
+/// An [`LshFamily`] signing records by their tokens.
+pub trait TokenLshFamily: LshFamily {
+    /// Signs a record given by the hashes of its distinct tokens and their weights, writing
+    /// its stored signature and band hashes. `scratch` is reused between records.
+    fn sign(
+        &self,
+        tokens: &[(u64, f64)],
+        scratch: &mut Vec<u32>,
+        signature: &mut [u32],
+        bands: &mut [u64],
+    );
+}
+
+/// Creates the LSH family of `scheme`.
+///
+/// ## Arguments
+///
+/// * `scheme` - How the signatures are computed.
+/// * `num_perm` - Number of permutations to use in the MinHash algorithm.
+/// * `num_bands` - Number of times to split each hash signature in the LSH algorithm
+///   (i.e., number of hash tables).
+/// * `signature_bits` - Number of bits of every MinHash value to keep for thresholding (1-32).
+/// * `weighting` - How tokens are weighted.
+pub fn new_family(
+    scheme: SignatureScheme,
+    num_perm: usize,
+    num_bands: usize,
+    signature_bits: u32,
+    weighting: TokenWeighting,
+) -> Box<dyn TokenLshFamily> {
+    match scheme {
+        SignatureScheme::SimHash64 => Box::new(SimHash::new(64, num_bands)),
+        SignatureScheme::SimHash128 => Box::new(SimHash::new(128, num_bands)),
+        SignatureScheme::MinHash | SignatureScheme::OnePermutation => Box::new(MinHash::new(
+            num_perm,
+            num_bands,
+            scheme,
+            signature_bits,
+            weighting != TokenWeighting::None,
+        )),
+    }
+}
+
+/// MinHash signature generator.
+///
+/// With [`SignatureScheme::MinHash`] every signature value comes from its own universal hash
+/// permutation. [`SignatureScheme::OnePermutation`] hashes each item once, keeps the minimum
+/// per bin and fills empty bins by optimal densification (Shrivastava, 2017), which gives the
+/// same collision probability per value at a fraction of the cost for long texts.
+///
+/// Weighted items are signed with Improved Consistent Weighted Sampling (Ioffe, 2010), where
+/// the collision probability is the weighted jaccard similarity instead.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct MinHash {
-    pub hash_values: Vec<u32>,
+    scheme: SignatureScheme,
+    weighted: bool,
     num_perm: usize,
+    permutations: Vec<(u64, u64)>,
+    num_bands: usize,
+    packing: BitPacking,
 }
 
 impl MinHash {
-    fn new(items: Vec<&str>, permutations: &Vec<(u64, u64)>) -> Self {
-        let num_perm = permutations.len();
-        let mut hash_values = vec![u32::MAX; num_perm];
-        for item in items {
-            let item_hash = calculate_hash(&item);
-            for (i, &(a, b)) in permutations.iter().enumerate() {
-                let hash = permute_hash(item_hash, a, b);
-                hash_values[i] = hash_values[i].min(hash);
+    fn new(
+        num_perm: usize,
+        num_bands: usize,
+        scheme: SignatureScheme,
+        signature_bits: u32,
+        weighted: bool,
+    ) -> Self {
+        let mut rng = StdRng::from_entropy();
+        Self::with_rng(
+            num_perm,
+            num_bands,
+            scheme,
+            signature_bits,
+            weighted,
+            &mut rng,
+        )
+    }
+
+    /// Like [`MinHash::new`], drawing the permutations from `rng`.
+    fn with_rng(
+        num_perm: usize,
+        num_bands: usize,
+        scheme: SignatureScheme,
+        signature_bits: u32,
+        weighted: bool,
+        rng: &mut impl Rng,
+    ) -> Self {
+        let num_hashes = match scheme {
+            SignatureScheme::OnePermutation => 1,
+            _ => num_perm,
+        };
+        let permutations = (0..num_hashes).map(|_| (rng.gen(), rng.gen())).collect();
+        MinHash {
+            scheme,
+            weighted,
+            num_perm,
+            permutations,
+            num_bands,
+            packing: BitPacking::new(signature_bits, num_perm),
+        }
+    }
+
+    /// Whether the parameters are consistent: as many permutations as the scheme needs,
+    /// 1 to `num_perm` bands and the bit packing of `num_perm` values.
+    fn is_valid(&self) -> bool {
+        let num_hashes = match self.scheme {
+            SignatureScheme::MinHash => self.num_perm,
+            SignatureScheme::OnePermutation if !self.weighted => 1,
+            _ => return false,
+        };
+        self.permutations.len() == num_hashes
+            && (1..=self.num_perm).contains(&self.num_bands)
+            && (1..=32).contains(&self.packing.bits)
+            && self.packing == BitPacking::new(self.packing.bits, self.num_perm)
+    }
+
+    /// Writes the signature of `items` into `hash_values` (one value per permutation).
+    fn sign_values(&self, items: &[(u64, f64)], hash_values: &mut [u32]) {
+        hash_values.fill(u32::MAX);
+        if self.weighted {
+            self.sign_weighted(items, hash_values);
+        } else if self.scheme == SignatureScheme::OnePermutation {
+            self.sign_one_permutation(items, hash_values);
+        } else {
+            for &(item_hash, _) in items {
+                for (value, &(a, b)) in hash_values.iter_mut().zip(&self.permutations) {
+                    *value = (*value).min(permute_hash(item_hash, a, b));
+                }
             }
         }
-        MinHash {
-            hash_values,
+    }
+
+    /// Writes the weighted signature of `items` (item hash and positive weight) into
+    /// `hash_values`. Every value is the item with the smallest ICWS sample under that
+    /// permutation, combined with its quantized weight.
+    fn sign_weighted(&self, items: &[(u64, f64)], hash_values: &mut [u32]) {
+        let items: Vec<(u64, f64)> = items
+            .iter()
+            .map(|&(item_hash, weight)| (item_hash, weight.ln()))
+            .collect();
+        for (value, &(a, b)) in hash_values.iter_mut().zip(&self.permutations) {
+            let mut min_sample = f64::INFINITY;
+            for &(item_hash, ln_weight) in &items {
+                let first = mix_hash(item_hash ^ a);
+                let second = mix_hash(first ^ b);
+                let r = gamma_sample(first);
+                let c = gamma_sample(second);
+                let beta = unit_sample(mix_hash(second) as u32);
+                let t = (ln_weight / r + beta).floor();
+                let sample = c.ln() - r * (t - beta + 1.0);
+                if sample < min_sample {
+                    min_sample = sample;
+                    *value = mix_hash(item_hash ^ (t as i64 as u64).wrapping_mul(b)) as u32;
+                }
+            }
+        }
+    }
+
+    fn sign_one_permutation(&self, items: &[(u64, f64)], hash_values: &mut [u32]) {
+        let (seed, densification_seed) = self.permutations[0];
+        let num_bins = hash_values.len() as u64;
+        let mut filled = vec![false; hash_values.len()];
+        for &(item_hash, _) in items {
+            let hash = mix_hash(item_hash ^ seed);
+            let bin = (((hash >> 32) * num_bins) >> 32) as usize;
+            hash_values[bin] = hash_values[bin].min(hash as u32);
+            filled[bin] = true;
+        }
+        if filled.iter().all(|&f| f) || !filled.iter().any(|&f| f) {
+            return;
+        }
+        for bin in 0..hash_values.len() {
+            if filled[bin] {
+                continue;
+            }
+            // Probe bins with a hash of (bin, attempt) shared by all records until a bin
+            // that was filled by an item is found, and borrow its value.
+            let mut attempt = 0u64;
+            loop {
+                let probe = mix_hash(densification_seed ^ ((bin as u64) << 32 | attempt));
+                let donor = (((probe >> 32) * num_bins) >> 32) as usize;
+                if filled[donor] {
+                    hash_values[bin] = hash_values[donor];
+                    break;
+                }
+                attempt += 1;
+            }
+        }
+    }
+}
+
+impl LshFamily for MinHash {
+    fn words(&self) -> usize {
+        self.packing.words
+    }
+
+    fn num_bands(&self) -> usize {
+        self.num_bands
+    }
+
+    fn similarity(&self, signature: &[u32], other: &[u32]) -> f64 {
+        self.packing.jaccard_similarity(signature, other)
+    }
+
+    fn params(&self) -> FamilyParams {
+        FamilyParams::MinHash(self.clone())
+    }
+}
+
+impl TokenLshFamily for MinHash {
+    /// Bands are formed from the full signature values before they are packed to
+    /// `signature_bits` per value.
+    fn sign(
+        &self,
+        tokens: &[(u64, f64)],
+        scratch: &mut Vec<u32>,
+        signature: &mut [u32],
+        bands: &mut [u64],
+    ) {
+        scratch.resize(self.num_perm, 0);
+        self.sign_values(tokens, scratch);
+        let band_size = self.num_perm / self.num_bands;
+        for (band, band_hash) in scratch.chunks_exact(band_size).zip(bands) {
+            *band_hash = calculate_band_hash(band);
+        }
+        self.packing.pack(scratch, signature);
+    }
+}
+
+/// Layout of the stored signatures, keeping only the lowest `bits` of every MinHash value
+/// (b-bit MinHash, Li & König 2010). Values are packed into `u32` words without straddling
+/// word boundaries.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BitPacking {
+    bits: u32,
+    num_perm: usize,
+    values_per_word: usize,
+    /// Number of words per stored signature
+    words: usize,
+    /// Every bit of each field except its highest
+    low_mask: u32,
+    /// The highest bit of each field
+    top_mask: u32,
+}
+
+impl BitPacking {
+    fn new(bits: u32, num_perm: usize) -> Self {
+        let values_per_word = (32 / bits) as usize;
+        let field_low = (1u32 << (bits - 1)) - 1;
+        let (low_mask, top_mask) = (0..values_per_word as u32).fold((0, 0), |(low, top), field| {
+            let shift = field * bits;
+            (low | field_low << shift, top | 1 << (shift + bits - 1))
+        });
+        BitPacking {
+            bits,
             num_perm,
+            values_per_word,
+            words: num_perm.div_ceil(values_per_word),
+            low_mask,
+            top_mask,
         }
     }
 
-    pub fn jaccard_similarity(&self, other: &MinHash) -> f64 {
-        let equal_count = self
-            .hash_values
-            .par_iter()
-            .zip(&other.hash_values)
-            .filter(|&(&a, &b)| a == b)
-            .count();
-        equal_count as f64 / self.num_perm as f64
+    fn pack(&self, hash_values: &[u32], packed: &mut [u32]) {
+        if self.bits == 32 {
+            packed.copy_from_slice(hash_values);
+            return;
+        }
+        let field_mask = (1u32 << self.bits) - 1;
+        for (word, values) in packed
+            .iter_mut()
+            .zip(hash_values.chunks(self.values_per_word))
+        {
+            *word = values.iter().enumerate().fold(0, |word, (field, &value)| {
+                word | (value & field_mask) << (field as u32 * self.bits)
+            });
+        }
+    }
+
+    /// Estimates the jaccard similarity of two stored signatures from the share of equal
+    /// values, correcting for values that are equal by chance in their lowest `bits`.
+    pub fn jaccard_similarity(&self, packed: &[u32], other: &[u32]) -> f64 {
+        if self.bits == 32 {
+            return count_equal(packed, other) as f64 / self.num_perm as f64;
+        }
+        let zero_fields: usize = packed
+            .iter()
+            .zip(other)
+            .map(|(&a, &b)| {
+                // The top bit of a field ends up set iff any bit of the field differs.
+                let diff = a ^ b;
+                let nonzero = ((diff & self.low_mask) + self.low_mask) | diff;
+                self.values_per_word - (nonzero & self.top_mask).count_ones() as usize
+            })
+            .sum();
+        // Unused fields of the last word are zero in both signatures.
+        let equal = zero_fields - (self.words * self.values_per_word - self.num_perm);
+        let chance = 0.5f64.powi(self.bits as i32);
+        let matched = equal as f64 / self.num_perm as f64;
+        ((matched - chance) / (1.0 - chance)).clamp(0.0, 1.0)
     }
 }
 
-#[derive(Clone)]
-/// Locality-Sensitive Hashing using MinHash for efficient similarity search.
-pub struct MinHashLSH<'a> {
-    /// A table for looking up full minhashes for jaccard similarity thresholding
-    pub minhash_map: HashMap<&'a str, MinHash>,
+/// Locality-Sensitive Hashing using MinHash (or any other [`LshFamily`]) for efficient
+/// similarity search.
+///
+/// Records are referred to by their dense index in the slice of texts the LSH was built from.
+pub struct MinHashLSH {
+    /// Row-major matrix of the stored signatures for similarity thresholding
+    signatures: Vec<u32>,
+    family: Box<dyn LshFamily>,
+    /// Row-major `num_records × num_bands` matrix of the band hashes of every record
+    band_hashes: Vec<u64>,
     /// Number of times to split the hash singature (number of banded hash tables)
-    band_size: usize,
+    num_bands: usize,
     /// Banded hash tables used to find candidates for similarity
-    hash_tables: Vec<HashMap<u64, Vec<&'a str>>>,
+    hash_tables: Vec<HashMap<u64, Vec<u32>>>,
 }
 
-impl<'a> MinHashLSH<'a> {
+impl MinHashLSH {
     /// Creates a new MinHashLSH instance.
     ///
+    /// Signatures and band hashes are computed in parallel over the records, so the full
+    /// MinHash signatures are never held for more than one record per worker.
+    ///
     /// ## Arguments
     ///
-    /// * `records` - The records to dedupe.
-    /// * `num_perm` - Number of permutations to use in the MinHash algorithm.
-    /// * `num_bands` - Number of times to split each hash signature in the LSH algorithm
-    /// (i.e., number of hash tables).
-    pub fn new(records: &'a Vec<Record>, num_perm: usize, num_bands: usize) -> Self {
-        let mut rng = StdRng::from_entropy();
-        let permutations: Vec<(u64, u64)> = (0..num_perm).map(|_| (rng.gen(), rng.gen())).collect();
-        let band_size = num_perm / num_bands;
-        let mut minhash_map: HashMap<&str, MinHash> = HashMap::with_capacity(records.len());
-        let mut hash_tables: Vec<HashMap<u64, Vec<&str>>> = vec![HashMap::new(); num_bands];
-        for Record { id, text } in records {
-            let items = text.split_whitespace().collect();
-            let minhash = MinHash::new(items, &permutations);
-            minhash_map.insert(&id, minhash.clone());
-            for (i, table) in hash_tables.iter_mut().enumerate() {
-                let start = i * band_size;
-                let end = start + band_size;
-                let band_hash = calculate_band_hash(&minhash.hash_values[start..end]);
-                table.entry(band_hash).or_insert_with(Vec::new).push(id);
-            }
-        }
+    /// * `texts` - The texts of the records to dedupe.
+    /// * `tokenizer` - How the texts are split into weighted tokens.
+    /// * `family` - How the records are signed, banded and compared (see [`new_family`]).
+    /// * `base` - Signatures and band hashes of records indexed by an earlier job with the
+    ///   same family, which come first in the index (empty matrices if there are none).
+    pub fn new(
+        texts: &[&str],
+        tokenizer: &Tokenizer,
+        family: Box<dyn TokenLshFamily>,
+        base: (Vec<u32>, Vec<u64>),
+    ) -> Self {
+        let (signatures, band_hashes) = sign_texts(texts, tokenizer, family.as_ref(), base);
+        Self::from_signatures(signatures, band_hashes, family)
+    }
+
+    /// Creates an index over embedding vectors, signed by random hyperplanes and compared by
+    /// cosine similarity. `base` is as for [`MinHashLSH::new`].
+    pub fn from_vectors(
+        vectors: &[Vec<f32>],
+        family: RandomHyperplanes,
+        base: (Vec<u32>, Vec<u64>),
+    ) -> Self {
+        let (signatures, band_hashes) = sign_vectors(vectors, &family, base);
+        Self::from_signatures(signatures, band_hashes, Box::new(family))
+    }
+
+    /// Builds the band tables over the given signatures (row-major matrices as returned by
+    /// [`MinHashLSH::signatures`]). Every band table is built by its own worker, so no table
+    /// is ever shared between threads.
+    pub fn from_signatures(
+        signatures: Vec<u32>,
+        band_hashes: Vec<u64>,
+        family: Box<dyn LshFamily>,
+    ) -> Self {
+        let num_bands = family.num_bands();
+        let num_records = band_hashes.len() / num_bands;
+        let hash_tables: Vec<HashMap<u64, Vec<u32>>> = (0..num_bands)
+            .into_par_iter()
+            .map(|i| {
+                let mut table: HashMap<u64, Vec<u32>> = HashMap::with_capacity(num_records);
+                for (idx, bands) in band_hashes.chunks_exact(num_bands).enumerate() {
+                    table.entry(bands[i]).or_default().push(idx as u32);
+                }
+                table
+            })
+            .collect();
         MinHashLSH {
-            minhash_map,
-            band_size,
+            signatures,
+            family,
+            band_hashes,
+            num_bands,
             hash_tables,
         }
     }
 
-    /// Query the LSH for (potentially) similar items.
+    /// Number of records in the index.
+    pub fn len(&self) -> usize {
+        self.band_hashes.len() / self.num_bands
+    }
+
+    /// The family the records were signed with.
+    pub fn family(&self) -> &dyn LshFamily {
+        self.family.as_ref()
+    }
+
+    /// The stored signatures and band hashes of all records, as row-major matrices.
+    pub fn signatures(&self) -> (&[u32], &[u64]) {
+        (&self.signatures, &self.band_hashes)
+    }
+
+    /// The stored signature of the record at index `idx`.
+    pub fn signature(&self, idx: u32) -> &[u32] {
+        let words = self.family.words();
+        let start = idx as usize * words;
+        &self.signatures[start..start + words]
+    }
+
+    /// The band hashes of the record at index `idx`.
+    fn bands(&self, idx: u32) -> &[u64] {
+        let start = idx as usize * self.num_bands;
+        &self.band_hashes[start..start + self.num_bands]
+    }
+
+    /// Finds the similar records of every record in the index from `first_query` on,
+    /// verifying each candidate pair only once. The result for a record holds the record
+    /// itself followed by the similar records with a higher index or an index below
+    /// `first_query`, so records before `first_query` are only compared to later ones.
     ///
     /// ## Arguments
     ///
-    /// * `minhash` - The MinHash instance to query for.
+    /// * `first_query` - Index of the first record to query (0 to join all records).
     /// * `threshold` - threshold (inclusive) for jaccard similarity to apply to query result (optional).
+    /// * `max_bucket_size` - Size above which a bucket is handled by `bucket_policy` (optional).
+    /// * `bucket_policy` - How to find candidates in oversized buckets.
     ///
-    pub fn query(&self, minhash: &MinHash, threshold: Option<f64>) -> Vec<&'a str> {
-        let candidates: HashSet<&'a str> =
-            self.hash_tables
-                .iter()
-                .enumerate()
-                .fold(HashSet::new(), |mut doc_set, (i, table)| {
-                    let start = i * self.band_size;
-                    let end = start + self.band_size;
-                    let band_hash = calculate_band_hash(&minhash.hash_values[start..end]);
-                    if let Some(docs) = table.get(&band_hash) {
-                        doc_set.extend(docs);
+    pub fn self_join(
+        &self,
+        first_query: u32,
+        threshold: Option<f64>,
+        max_bucket_size: Option<usize>,
+        bucket_policy: BucketPolicy,
+    ) -> Vec<(u32, Vec<u32>)> {
+        let max_bucket_size = max_bucket_size.unwrap_or(usize::MAX);
+        let samples = match bucket_policy {
+            BucketPolicy::Sample => self.sample_buckets(max_bucket_size),
+            _ => Vec::new(),
+        };
+        (first_query..self.len() as u32)
+            .into_par_iter()
+            .map(|idx| {
+                let packed = self.signature(idx);
+                let mut similar = Vec::new();
+                let mut grouped: Vec<u32> = Vec::new();
+                for (band, (table, band_hash)) in
+                    self.hash_tables.iter().zip(self.bands(idx)).enumerate()
+                {
+                    let docs = &table[band_hash];
+                    let queried = docs.partition_point(|&doc| doc < first_query);
+                    if docs.len() <= max_bucket_size {
+                        similar.extend_from_slice(&docs[..queried]);
+                        similar.extend_from_slice(&docs[docs.partition_point(|&doc| doc <= idx)..]);
+                        continue;
                     }
-                    doc_set
-                });
-        if let Some(threshold) = threshold {
-            candidates
-                .into_par_iter()
-                .filter_map(|id| {
-                    let candidate_hash = &self.minhash_map[id];
-                    if minhash.jaccard_similarity(candidate_hash) >= threshold {
-                        Some(id)
-                    } else {
-                        None
+                    match bucket_policy {
+                        BucketPolicy::Skip => {}
+                        // Every record is compared against the whole sample, so pairs of
+                        // sampled records are found twice, which is harmless.
+                        BucketPolicy::Sample => similar
+                            .extend(samples[band][band_hash].iter().filter(|&&doc| doc != idx)),
+                        // The first queried record groups the bucket.
+                        BucketPolicy::Group if docs[queried] == idx => {
+                            grouped.extend(docs.iter().filter(|&&doc| doc != idx))
+                        }
+                        BucketPolicy::Group => {}
                     }
-                })
-                .collect()
-        } else {
-            candidates.into_iter().collect()
+                }
+                similar.sort_unstable();
+                similar.dedup();
+                if let Some(threshold) = threshold {
+                    similar.retain(|&other| {
+                        self.family.similarity(packed, self.signature(other)) >= threshold
+                    });
+                }
+                similar.extend(grouped);
+                similar.push(idx);
+                (idx, similar)
+            })
+            .collect()
+    }
+
+    /// Finds the indexed records similar to every record of another dataset, signed with the
+    /// same family (see [`sign_texts`]). The result for a record holds the indices of the
+    /// similar records with their estimated similarity, most similar first.
+    ///
+    /// ## Arguments
+    ///
+    /// * `signatures` - Stored signatures of the queried records, as a row-major matrix.
+    /// * `band_hashes` - Band hashes of the queried records, as a row-major matrix.
+    /// * `threshold` - Similarity threshold (inclusive) of the returned records.
+    /// * `max_bucket_size` - Size above which a bucket is handled by `bucket_policy` (optional).
+    /// * `bucket_policy` - How to find candidates in oversized buckets, grouping is treated
+    ///   like skipping.
+    ///
+    pub fn join(
+        &self,
+        signatures: &[u32],
+        band_hashes: &[u64],
+        threshold: f64,
+        max_bucket_size: Option<usize>,
+        bucket_policy: BucketPolicy,
+    ) -> Vec<Vec<(u32, f64)>> {
+        let max_bucket_size = max_bucket_size.unwrap_or(usize::MAX);
+        let samples = match bucket_policy {
+            BucketPolicy::Sample => self.sample_buckets(max_bucket_size),
+            _ => Vec::new(),
+        };
+        signatures
+            .par_chunks_exact(self.family.words())
+            .zip(band_hashes.par_chunks_exact(self.num_bands))
+            .map(|(signature, bands)| {
+                let mut candidates = Vec::new();
+                for (band, (table, band_hash)) in self.hash_tables.iter().zip(bands).enumerate() {
+                    let Some(docs) = table.get(band_hash) else {
+                        continue;
+                    };
+                    if docs.len() <= max_bucket_size {
+                        candidates.extend_from_slice(docs);
+                    } else if let BucketPolicy::Sample = bucket_policy {
+                        candidates.extend_from_slice(&samples[band][band_hash]);
+                    }
+                }
+                candidates.sort_unstable();
+                candidates.dedup();
+                let mut similar: Vec<(u32, f64)> = candidates
+                    .into_iter()
+                    .map(|doc| (doc, self.family.similarity(signature, self.signature(doc))))
+                    .filter(|&(_, similarity)| similarity >= threshold)
+                    .collect();
+                similar.sort_by(|a, b| b.1.total_cmp(&a.1));
+                similar
+            })
+            .collect()
+    }
+
+    /// Picks `sample_size` records of every bucket holding more than `sample_size` records.
+    /// The records with the lowest hash of their index are picked, so buckets of different
+    /// bands holding mostly the same records share most of their sample.
+    fn sample_buckets(&self, sample_size: usize) -> Vec<HashMap<u64, Vec<u32>>> {
+        self.hash_tables
+            .par_iter()
+            .map(|table| {
+                table
+                    .iter()
+                    .filter(|(_, docs)| docs.len() > sample_size)
+                    .map(|(&band_hash, docs)| {
+                        let mut sample = docs.clone();
+                        sample.select_nth_unstable_by_key(sample_size, |&doc| mix_hash(doc as u64));
+                        sample.truncate(sample_size);
+                        (band_hash, sample)
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Number of buckets over all bands holding more than `max_bucket_size` records.
+    pub fn oversized_buckets(&self, max_bucket_size: usize) -> usize {
+        self.hash_tables
+            .iter()
+            .map(|table| {
+                table
+                    .values()
+                    .filter(|docs| docs.len() > max_bucket_size)
+                    .count()
+            })
+            .sum()
+    }
+
+    /// The largest buckets of every band (up to `per_band` of them, each holding more than
+    /// one record), largest first.
+    pub fn largest_buckets(&self, per_band: usize) -> Vec<Vec<&[u32]>> {
+        self.hash_tables
+            .par_iter()
+            .map(|table| {
+                let mut buckets: Vec<&[u32]> = table
+                    .values()
+                    .filter(|docs| docs.len() > 1)
+                    .map(Vec::as_slice)
+                    .collect();
+                if buckets.len() > per_band {
+                    buckets.select_nth_unstable_by_key(per_band, |docs| Reverse(docs.len()));
+                    buckets.truncate(per_band);
+                }
+                buckets.sort_unstable_by_key(|docs| Reverse(docs.len()));
+                buckets
+            })
+            .collect()
+    }
+}
+
+/// Computes the stored signatures and band hashes of `texts`, appending them to the row-major
+/// matrices of `base` (empty matrices to sign the texts on their own).
+pub fn sign_texts(
+    texts: &[&str],
+    tokenizer: &Tokenizer,
+    family: &dyn TokenLshFamily,
+    base: (Vec<u32>, Vec<u64>),
+) -> (Vec<u32>, Vec<u64>) {
+    sign_records(texts, family, base, |text, scratch, signature, bands| {
+        let tokens = tokenizer.token_weights(text);
+        family.sign(&tokens, scratch, signature, bands);
+    })
+}
+
+/// Like [`sign_texts`], for embedding vectors signed by random hyperplanes.
+pub fn sign_vectors(
+    vectors: &[Vec<f32>],
+    family: &RandomHyperplanes,
+    base: (Vec<u32>, Vec<u64>),
+) -> (Vec<u32>, Vec<u64>) {
+    sign_records(
+        vectors,
+        family,
+        base,
+        |vector, scratch, signature, bands| {
+            family.sign(vector, scratch, signature, bands);
+        },
+    )
+}
+
+/// Computes the stored signatures and band hashes of all `records` in parallel, appending
+/// them to the row-major matrices of `base`.
+fn sign_records<T: Sync>(
+    records: &[T],
+    family: &dyn LshFamily,
+    base: (Vec<u32>, Vec<u64>),
+    sign: impl Fn(&T, &mut Vec<u32>, &mut [u32], &mut [u64]) + Sync,
+) -> (Vec<u32>, Vec<u64>) {
+    let (words, num_bands) = (family.words(), family.num_bands());
+    let (mut signatures, mut band_hashes) = base;
+    let (base_words, base_bands) = (signatures.len(), band_hashes.len());
+    signatures.resize(base_words + records.len() * words, 0);
+    band_hashes.resize(base_bands + records.len() * num_bands, 0);
+    signatures[base_words..]
+        .par_chunks_mut(words)
+        .zip(band_hashes[base_bands..].par_chunks_mut(num_bands))
+        .zip(records.par_iter())
+        .for_each_init(Vec::new, |scratch, ((signature, bands), record)| {
+            sign(record, scratch, signature, bands)
+        });
+    (signatures, band_hashes)
+}
+
+/// Uniform sample in (0, 1) from 32 random bits.
+#[inline]
+fn unit_sample(bits: u32) -> f64 {
+    (bits as f64 + 0.5) / 4294967296.0
+}
+
+/// Gamma(2, 1) sample from 64 random bits, as the sum of two exponential samples.
+#[inline]
+fn gamma_sample(bits: u64) -> f64 {
+    -(unit_sample(bits as u32) * unit_sample((bits >> 32) as u32)).ln()
+}
+
+/// Number of signature values compared per step, sized so the comparison loop compiles
+/// down to a few SIMD instructions.
+const LANES: usize = 16;
+
+#[inline]
+fn count_equal(a: &[u32], b: &[u32]) -> usize {
+    let mut lanes = [0u32; LANES];
+    let mut a_chunks = a.chunks_exact(LANES);
+    let mut b_chunks = b.chunks_exact(LANES);
+    for (x, y) in (&mut a_chunks).zip(&mut b_chunks) {
+        for lane in 0..LANES {
+            lanes[lane] += (x[lane] == y[lane]) as u32;
         }
     }
+    let remainder = a_chunks
+        .remainder()
+        .iter()
+        .zip(b_chunks.remainder())
+        .filter(|&(x, y)| x == y)
+        .count();
+    lanes.iter().map(|&count| count as usize).sum::<usize>() + remainder
 }
 
 #[inline]
-fn calculate_hash<T: Hash>(t: &T) -> u64 {
+pub fn calculate_hash<T: Hash>(t: &T) -> u64 {
     let mut s = FxHasher::default();
     t.hash(&mut s);
     s.finish()
@@ -134,11 +798,394 @@ fn permute_hash(hash: u64, a: u64, b: u64) -> u32 {
     ((a.wrapping_mul(hash).wrapping_add(b)) >> 32) as u32
 }
 
+/// SplitMix64 finalizer, spreads entropy over all bits of the hash.
 #[inline]
-fn calculate_band_hash(band: &[u32]) -> u64 {
+pub fn mix_hash(mut hash: u64) -> u64 {
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d049bb133111eb);
+    hash ^ (hash >> 31)
+}
+
+/// The values (or bits) of a signature of `len` that band `band` of `num_bands` covers: the
+/// bands cover every value once and their widths differ by at most one.
+pub fn band_range(band: usize, num_bands: usize, len: usize) -> Range<usize> {
+    band * len / num_bands..(band + 1) * len / num_bands
+}
+
+#[inline]
+pub fn calculate_band_hash(band: &[u32]) -> u64 {
     let mut hasher = FxHasher::default();
     for &value in band {
         hasher.write_u32(value);
     }
     hasher.finish()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dedup::DeduplicationTable;
+    use crate::prefilter::PrunedTokens;
+    use std::collections::HashSet;
+    use std::time::Instant;
+
+    /// `count` texts of `words` words drawn from a vocabulary of 5000, the same on every call
+    fn synthetic_texts(count: usize, words: usize) -> Vec<String> {
+        let mut rng = StdRng::seed_from_u64(7);
+        (0..count)
+            .map(|_| {
+                let words: Vec<String> = (0..words)
+                    .map(|_| format!("w{}", rng.gen_range(0..5000)))
+                    .collect();
+                words.join(" ")
+            })
+            .collect()
+    }
+
+    /// `texts.len()` groups of `group_size` texts, every text of a group replacing a different
+    /// word of the same text of `texts`
+    fn near_duplicates(texts: &[String], group_size: usize) -> Vec<String> {
+        texts
+            .iter()
+            .flat_map(|text| {
+                let words: Vec<&str> = text.split(' ').collect();
+                (0..group_size).map(move |member| {
+                    let mut words = words.clone();
+                    let replaced = format!("v{member}");
+                    let len = words.len();
+                    words[member % len] = &replaced;
+                    words.join(" ")
+                })
+            })
+            .collect()
+    }
+
+    /// Thread counts from 1 up to the available parallelism, doubling
+    fn thread_counts() -> Vec<usize> {
+        let max_threads = std::thread::available_parallelism().map_or(1, usize::from);
+        let mut counts: Vec<usize> = (0..)
+            .map(|power| 1 << power)
+            .take_while(|&threads| threads < max_threads)
+            .collect();
+        counts.push(max_threads);
+        counts
+    }
+
+    /// A one-permutation family with a fixed seed, so the assertions hold on every run
+    fn one_permutation(num_perm: usize) -> MinHash {
+        MinHash::with_rng(
+            num_perm,
+            num_perm / 4,
+            SignatureScheme::OnePermutation,
+            32,
+            false,
+            &mut StdRng::seed_from_u64(7),
+        )
+    }
+
+    fn items(range: std::ops::Range<u64>) -> Vec<(u64, f64)> {
+        range.map(|item| (calculate_hash(&item), 1.0)).collect()
+    }
+
+    fn sign(minhash: &MinHash, items: &[(u64, f64)]) -> Vec<u32> {
+        let mut values = vec![0; minhash.num_perm];
+        minhash.sign_values(items, &mut values);
+        values
+    }
+
+    #[test]
+    fn one_permutation_single_token() {
+        let minhash = one_permutation(64);
+        let values = sign(&minhash, &items(0..1));
+        // The only filled bin lends its value to every other one.
+        let hash = mix_hash(calculate_hash(&0u64) ^ minhash.permutations[0].0) as u32;
+        assert!(values.iter().all(|&value| value == hash));
+        assert_eq!(values, sign(&minhash, &items(0..1)));
+        assert!(sign(&minhash, &[]).iter().all(|&value| value == u32::MAX));
+    }
+
+    #[test]
+    fn one_permutation_more_tokens_than_bins() {
+        let minhash = one_permutation(64);
+        let items = items(0..10_000);
+        let seed = minhash.permutations[0].0;
+        let mut expected = vec![u32::MAX; 64];
+        for &(item_hash, _) in &items {
+            let hash = mix_hash(item_hash ^ seed);
+            let bin = (((hash >> 32) * 64) >> 32) as usize;
+            expected[bin] = expected[bin].min(hash as u32);
+        }
+        // Every bin is filled, so no value is borrowed.
+        assert!(expected.iter().all(|&value| value != u32::MAX));
+        assert_eq!(sign(&minhash, &items), expected);
+    }
+
+    #[test]
+    fn one_permutation_densification_is_deterministic() {
+        let minhash = one_permutation(256);
+        let sparse = items(0..20);
+        let values = sign(&minhash, &sparse);
+        // Empty bins borrow the value of a filled bin, the same one on every call and for
+        // every copy of the family, e.g. after an index snapshot round trip.
+        let copy: MinHash =
+            serde_json::from_str(&serde_json::to_string(&minhash).unwrap()).unwrap();
+        assert_eq!(values, sign(&copy, &sparse));
+        let mut reversed = sparse.clone();
+        reversed.reverse();
+        assert_eq!(values, sign(&minhash, &reversed));
+        let distinct: HashSet<u32> = values.iter().copied().collect();
+        assert!(distinct.len() <= sparse.len());
+        // Records sharing most of their tokens agree on most of their values, empty bins
+        // included.
+        let similar = sign(&minhash, &items(0..21));
+        assert!(count_equal(&values, &similar) > 200);
+        // A record on its own hashes to entirely different values.
+        let other = sign(&minhash, &items(1000..1020));
+        assert!(count_equal(&values, &other) < 56);
+    }
+
+    /// The lowest `bits` of every value of a stored signature
+    fn unpack(packing: &BitPacking, packed: &[u32]) -> Vec<u32> {
+        let field_mask = u32::MAX >> (32 - packing.bits);
+        (0..packing.num_perm)
+            .map(|idx| {
+                let word = packed[idx / packing.values_per_word];
+                let shift = (idx % packing.values_per_word) as u32 * packing.bits;
+                (word >> shift) & field_mask
+            })
+            .collect()
+    }
+
+    fn pack(packing: &BitPacking, values: &[u32]) -> Vec<u32> {
+        let mut packed = vec![0; packing.words];
+        packing.pack(values, &mut packed);
+        packed
+    }
+
+    /// Pairs of `num_perm` random values, equal with probability `jaccard`
+    fn random_signatures(rng: &mut StdRng, num_perm: usize, jaccard: f64) -> (Vec<u32>, Vec<u32>) {
+        (0..num_perm)
+            .map(|_| {
+                let value: u32 = rng.gen();
+                match rng.gen_bool(jaccard) {
+                    true => (value, value),
+                    false => (value, rng.gen()),
+                }
+            })
+            .unzip()
+    }
+
+    #[test]
+    fn bit_packing_round_trip() {
+        let mut rng = StdRng::seed_from_u64(7);
+        for bits in 1..=32 {
+            for num_perm in [1, 31, 64, 100] {
+                let packing = BitPacking::new(bits, num_perm);
+                assert_eq!(packing.words, num_perm.div_ceil((32 / bits) as usize));
+                let values: Vec<u32> = (0..num_perm).map(|_| rng.gen()).collect();
+                let low_bits: Vec<u32> = values
+                    .iter()
+                    .map(|&value| value & (u32::MAX >> (32 - bits)))
+                    .collect();
+                assert_eq!(
+                    unpack(&packing, &pack(&packing, &values)),
+                    low_bits,
+                    "{bits} bits, {num_perm} values"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn bit_packing_estimator() {
+        let mut rng = StdRng::seed_from_u64(7);
+        for bits in 1..=32 {
+            let packing = BitPacking::new(bits, 1024);
+            let (a, b) = random_signatures(&mut rng, 1024, 0.0);
+            let (a, b) = (pack(&packing, &a), pack(&packing, &b));
+            assert_eq!(packing.jaccard_similarity(&a, &a), 1.0, "{bits} bits");
+            // Values of disjoint sets only agree by chance, which the estimator corrects for.
+            let disjoint = packing.jaccard_similarity(&a, &b);
+            assert!(disjoint < 0.15, "{bits} bits: {disjoint}");
+            let (a, b) = random_signatures(&mut rng, 1024, 0.6);
+            let estimate = packing.jaccard_similarity(&pack(&packing, &a), &pack(&packing, &b));
+            assert!((estimate - 0.6).abs() < 0.1, "{bits} bits: {estimate}");
+        }
+    }
+
+    /// An index over `copies` copies of a text, which share a bucket in every band
+    fn copies(copies: usize) -> MinHashLSH {
+        let texts = vec!["a b c d e f"; copies];
+        let tokenizer = Tokenizer::new(&texts, PrunedTokens::default(), TokenWeighting::None);
+        let family = new_family(SignatureScheme::MinHash, 16, 4, 32, TokenWeighting::None);
+        MinHashLSH::new(&texts, &tokenizer, family, Default::default())
+    }
+
+    /// The records with the lowest hash of their index among the first `num_records`
+    fn lowest_hashes(num_records: u32, count: usize) -> Vec<u32> {
+        let mut records: Vec<u32> = (0..num_records).collect();
+        records.sort_unstable_by_key(|&doc| mix_hash(doc as u64));
+        records.truncate(count);
+        records.sort_unstable();
+        records
+    }
+
+    #[test]
+    fn buckets_within_max_bucket_size_are_compared() {
+        let results = copies(10).self_join(0, Some(0.5), Some(10), BucketPolicy::Skip);
+        assert_eq!(results[0], (0, vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 0]));
+        assert_eq!(results[9], (9, vec![9]));
+    }
+
+    #[test]
+    fn oversized_buckets_are_skipped() {
+        let results = copies(10).self_join(0, Some(0.5), Some(9), BucketPolicy::Skip);
+        for (idx, similar) in results {
+            assert_eq!(similar, [idx]);
+        }
+    }
+
+    #[test]
+    fn oversized_buckets_are_sampled() {
+        let sample = lowest_hashes(10, 3);
+        let results = copies(10).self_join(0, Some(0.5), Some(3), BucketPolicy::Sample);
+        for (idx, similar) in results {
+            let expected: Vec<u32> = sample
+                .iter()
+                .copied()
+                .filter(|&doc| doc != idx)
+                .chain([idx])
+                .collect();
+            assert_eq!(similar, expected, "record {idx}");
+        }
+    }
+
+    #[test]
+    fn samples_are_the_same_on_every_run() {
+        // Indexes of the same records share their samples, whatever their permutations.
+        for _ in 0..3 {
+            let samples = copies(100).sample_buckets(7);
+            assert_eq!(samples.len(), 4);
+            for band in samples {
+                let samples: Vec<&Vec<u32>> = band.values().collect();
+                assert_eq!(samples.len(), 1);
+                let mut sample = samples[0].clone();
+                sample.sort_unstable();
+                assert_eq!(sample, lowest_hashes(100, 7));
+            }
+        }
+    }
+
+    #[test]
+    fn oversized_buckets_are_grouped_by_their_first_record() {
+        let results = copies(10).self_join(0, Some(0.5), Some(3), BucketPolicy::Group);
+        // Every band groups the same bucket again, which is harmless.
+        let mut grouped = results[0].1.clone();
+        grouped.sort_unstable();
+        grouped.dedup();
+        assert_eq!(grouped, (0..10).collect::<Vec<u32>>());
+        for (idx, similar) in &results[1..] {
+            assert_eq!(similar, &[*idx]);
+        }
+    }
+
+    #[test]
+    fn join_only_pairs_queries_with_reference_records() {
+        let reference = ["a b c d e f", "g h i j k l"];
+        // The last two queries are copies of each other but of no reference record.
+        let queries = ["a b c d e f", "p q r s t u", "p q r s t u"];
+        let tokenizer = Tokenizer::new(&reference, PrunedTokens::default(), TokenWeighting::None);
+        let family = new_family(SignatureScheme::MinHash, 64, 16, 32, TokenWeighting::None);
+        let (signatures, band_hashes) =
+            sign_texts(&queries, &tokenizer, family.as_ref(), Default::default());
+        let lsh = MinHashLSH::new(&reference, &tokenizer, family, Default::default());
+        let matches = lsh.join(
+            &signatures,
+            &band_hashes,
+            0.5,
+            None,
+            BucketPolicy::default(),
+        );
+        assert_eq!(matches, [vec![(0, 1.0)], vec![], vec![]]);
+    }
+
+    #[test]
+    #[ignore = "benchmark, run with `cargo test --release -- --ignored --nocapture`"]
+    fn index_construction_scaling() {
+        let texts = synthetic_texts(50_000, 200);
+        let texts: Vec<&str> = texts.iter().map(String::as_str).collect();
+        let tokenizer = Tokenizer::new(&texts, PrunedTokens::default(), TokenWeighting::None);
+        let mut baseline = None;
+        for threads in thread_counts() {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap();
+            let start = Instant::now();
+            let lsh = pool.install(|| {
+                let family =
+                    new_family(SignatureScheme::MinHash, 128, 32, 32, TokenWeighting::None);
+                MinHashLSH::new(&texts, &tokenizer, family, Default::default())
+            });
+            let secs = start.elapsed().as_secs_f64();
+            let baseline = *baseline.get_or_insert(secs);
+            println!(
+                "{threads} thread(s): index of {} records built in {secs:.3} secs ({:.2}x)",
+                lsh.len(),
+                baseline / secs
+            );
+        }
+    }
+
+    #[test]
+    #[ignore = "benchmark, run with `cargo test --release -- --ignored --nocapture`"]
+    fn signature_comparison() {
+        let mut rng = StdRng::seed_from_u64(7);
+        // Signatures of 128 values sharing about half of them.
+        let pairs: Vec<(Vec<u32>, Vec<u32>)> = (0..100_000)
+            .map(|_| {
+                let a: Vec<u32> = (0..128).map(|_| rng.gen_range(0..4)).collect();
+                let b: Vec<u32> = (0..128).map(|_| rng.gen_range(0..4)).collect();
+                (a, b)
+            })
+            .collect();
+        let time = |name: &str, count: &dyn Fn(&[u32], &[u32]) -> usize| {
+            let start = Instant::now();
+            let equal: usize = (0..10)
+                .map(|_| pairs.iter().map(|(a, b)| count(a, b)).sum::<usize>())
+                .sum();
+            println!(
+                "{name}: {:.3} secs for 1M comparisons ({equal} equal values)",
+                start.elapsed().as_secs_f64()
+            );
+        };
+        time("par_iter per comparison", &|a, b| {
+            a.par_iter().zip(b).filter(|&(x, y)| x == y).count()
+        });
+        time("sequential iterator", &|a, b| {
+            a.iter().zip(b).filter(|&(x, y)| x == y).count()
+        });
+        time("count_equal", &count_equal);
+    }
+
+    #[test]
+    #[ignore = "benchmark, run with `cargo test --release -- --ignored --nocapture`"]
+    fn batched_verification() {
+        for group_size in [2, 11, 101, 1001] {
+            let texts = near_duplicates(&synthetic_texts(60_000 / group_size, 80), group_size);
+            let texts: Vec<&str> = texts.iter().map(String::as_str).collect();
+            let tokenizer = Tokenizer::new(&texts, PrunedTokens::default(), TokenWeighting::None);
+            let family = new_family(SignatureScheme::MinHash, 128, 16, 32, TokenWeighting::None);
+            let lsh = MinHashLSH::new(&texts, &tokenizer, family, Default::default());
+            let start = Instant::now();
+            let table = DeduplicationTable::new(&lsh, 0, Some(0.7), None, BucketPolicy::default());
+            println!(
+                "~{} candidates/query: {} records in {} groups, verified in {:.3} secs",
+                group_size - 1,
+                lsh.len(),
+                table.grouped_records().len(),
+                start.elapsed().as_secs_f64()
+            );
+        }
+    }
+}
@@ -1,16 +1,34 @@
+mod decontaminate;
 mod dedup;
+mod ensemble;
+mod forest;
+mod hyperplane;
+mod join;
 mod lsh;
+mod neighbors;
+mod prefilter;
+mod segments;
+mod simhash;
+mod snapshot;
+mod substrings;
 mod util;
 
+use crate::hyperplane::RandomHyperplanes;
+use crate::lsh::{FamilyParams, LshFamily, MinHashLSH};
+use crate::prefilter::{ExactDuplicates, PrunedTokens, Tokenizer};
+use crate::snapshot::SnapshotHeader;
+use crate::util::Corpus;
 use lambda_runtime::{run, service_fn, Error, LambdaEvent};
 use lazy_static::lazy_static;
-use lsh_dedup_service::dto::DedupConfig;
+use lsh_dedup_service::dto::{DedupConfig, JobMode, SimilarityMeasure};
 use lsh_dedup_service::error::ServiceError;
 use lsh_dedup_service::response::make_response_payload;
 use lsh_dedup_service::util::get_region;
+use rayon::prelude::*;
 use rusoto_core::{Client, Region};
 use rusoto_s3::S3Client;
 use serde_json::{json, Value};
+use std::iter;
 
 lazy_static! {
     // AWS Region
@@ -25,29 +43,342 @@ async fn main() -> Result<(), Error> {
 
 async fn process(event: LambdaEvent<DedupConfig>) -> Result<Value, Error> {
     let (config, _context) = event.into_parts();
-    let result = dedup(config).await;
+    let result = run_job(config).await;
     make_response_payload(result)
 }
 
-async fn dedup(config: DedupConfig) -> Result<Value, ServiceError> {
-    let start = std::time::Instant::now();
+async fn run_job(config: DedupConfig) -> Result<Value, ServiceError> {
+    config.validate()?;
     let client = S3Client::new_with_client(Client::shared(), REGION.clone());
-    let records = util::pull_data_file(&client, &config.data).await?;
-    println!(
-        "File downloaded in {:.4} secs",
-        (std::time::Instant::now() - start).as_secs_f64()
-    );
+    match config.mode {
+        JobMode::Dedup => dedup(&client, config).await,
+        JobMode::Join => join::join(&client, config).await,
+        JobMode::Decontaminate => decontaminate::decontaminate(&client, config).await,
+        JobMode::Neighbors => neighbors::neighbors(&client, config).await,
+        JobMode::Segments => segments::segments(&client, config).await,
+        JobMode::Substrings => substrings::substrings(&client, config).await,
+    }
+}
+
+async fn dedup(client: &S3Client, config: DedupConfig) -> Result<Value, ServiceError> {
+    let mut corpus = util::load_corpus(client, &config, &config.data).await?;
+    let output = util::resolve_output_file(&config, &corpus.sources)?;
+    let index_file = util::resolve_index_file(&config, &corpus.sources, &output)?;
+    let base = match &config.base_index {
+        Some(source) => Some(snapshot::pull_index_snapshot(client, source).await?),
+        None => None,
+    };
     let start = std::time::Instant::now();
-    let lsh = lsh::MinHashLSH::new(&records, config.num_perm, config.num_bands);
+    let (base_header, base_records, base_signatures) = match base {
+        Some(mut snapshot) => {
+            let records = std::mem::take(&mut snapshot.header.records);
+            let signatures = (snapshot.signatures, snapshot.band_hashes);
+            (Some(snapshot.header), records, signatures)
+        }
+        None => Default::default(),
+    };
+    corpus.duplicate_ids +=
+        corpus.enforce_unique_ids_against(&base_records.ids, config.duplicate_ids)?;
+    let num_base = base_records.ids.len();
+    let SignedCorpus {
+        tokenizer,
+        short,
+        exact,
+        family,
+        signatures,
+        band_hashes,
+    } = sign_corpus(&config, &corpus, base_header.as_ref(), base_signatures)?;
     println!(
         "Hashed records in {:.4} secs",
         (std::time::Instant::now() - start).as_secs_f64()
     );
-    let dedup_table = dedup::DeduplicationTable::new(lsh, Some(config.threshold));
+    let ids: Vec<&str> = base_records
+        .ids
+        .iter()
+        .map(String::as_str)
+        .chain(
+            exact
+                .representatives
+                .iter()
+                .map(|&idx| corpus.records[idx as usize].id.as_str()),
+        )
+        .collect();
+    // Containment is found by the LSH Ensemble, which needs no band tables.
+    let (dedup_table, lsh) = match config.similarity {
+        SimilarityMeasure::Jaccard => {
+            let lsh = MinHashLSH::from_signatures(signatures, band_hashes, family);
+            let dedup_table = dedup::DeduplicationTable::new(
+                &lsh,
+                num_base as u32,
+                Some(config.threshold()?),
+                config.max_bucket_size,
+                config.bucket_policy,
+            );
+            (dedup_table, Some(lsh))
+        }
+        SimilarityMeasure::Containment => {
+            let sizes: Vec<u32> = exact
+                .representatives
+                .par_iter()
+                .map(|&idx| {
+                    let text = &corpus.records[idx as usize].text;
+                    tokenizer.token_weights(text).len() as u32
+                })
+                .collect();
+            let num_partitions = config
+                .num_partitions
+                .unwrap_or(ensemble::DEFAULT_PARTITIONS);
+            let num_records = sizes.len();
+            let ensemble = ensemble::LshEnsemble::new(signatures, family, sizes, num_partitions);
+            let query_results = ensemble.self_join(config.threshold()?);
+            let dedup_table =
+                dedup::DeduplicationTable::from_query_results(num_records, query_results);
+            (dedup_table, None)
+        }
+    };
+    let buckets = lsh
+        .as_ref()
+        .map(|lsh| util::describe_buckets(lsh, &ids, config.max_bucket_size));
     println!(
         "Dedupe completed in {:.4} secs",
         (std::time::Instant::now() - start).as_secs_f64()
     );
-    let output_data = util::push_result_file(&client, config.data.bucket, config.data.key, dedup_table).await?;
-    Ok(json!({ "taskId": config.task_id, "data": output_data }))
+    let short_groups =
+        prefilter::group_short_records(&corpus.records, &short, config.short_text_policy);
+    let exact_matches = prefilter::mark_exact_matches(
+        corpus.records.len(),
+        exact.members.iter().chain(&short_groups),
+    );
+    let labels = match index_file {
+        Some(_) => dedup_table.label_clusters(&base_records.clusters),
+        None => Vec::new(),
+    };
+    let index_clusters = index_file.as_ref().map(|_| {
+        let mut index_clusters: Vec<Option<util::IndexCluster>> = iter::repeat_with(|| None)
+            .take(corpus.records.len())
+            .collect();
+        for (&(label, existing), members) in labels.iter().zip(&exact.members) {
+            for &idx in members {
+                index_clusters[idx as usize] = Some(util::IndexCluster {
+                    label,
+                    existing_id: existing.map(|idx| ids[idx as usize]),
+                });
+            }
+        }
+        index_clusters
+    });
+    let joined_existing = index_clusters.as_ref().map_or(0, |index_clusters| {
+        index_clusters
+            .iter()
+            .flatten()
+            .filter(|cluster| cluster.existing_id.is_some())
+            .count()
+    });
+    // The base records have no members, so groups of base records only are dropped.
+    let members: Vec<Vec<u32>> = iter::repeat_with(Vec::new)
+        .take(num_base)
+        .chain(exact.members.iter().cloned())
+        .collect();
+    let mut groups = dedup_table.grouped_records_with(&members);
+    groups.retain(|group| !group.is_empty());
+    groups.extend(short_groups);
+    let output_data = util::push_result_file(
+        client,
+        &output,
+        &corpus,
+        groups,
+        &exact_matches,
+        index_clusters.as_deref(),
+    )
+    .await?;
+    let rejected_data = util::push_rejected_rows(client, &output, &corpus, "rejected").await?;
+    let short_data =
+        util::push_short_records(client, &output, &corpus, &tokenizer.pruned, &short).await?;
+    let base_data = match &config.base_index {
+        Some(source) => json!({
+            "bucket": source.bucket,
+            "key": source.key,
+            "records": num_base,
+            "joinedExisting": joined_existing,
+        }),
+        None => Value::Null,
+    };
+    // Containment jobs can't save an index, so there's an LSH whenever there's an index file.
+    let index_data = match (&index_file, &lsh) {
+        (Some(target), Some(lsh)) => {
+            let clusters: Vec<u32> = labels.iter().map(|&(label, _)| label).collect();
+            let mut records = base_records;
+            records.extend(&corpus, &exact.representatives, &clusters);
+            let snapshot = snapshot::encode_snapshot(config.task_id, lsh, &tokenizer, records)?;
+            snapshot::push_index_snapshot(client, target, snapshot, lsh.len()).await?
+        }
+        _ => Value::Null,
+    };
+    Ok(json!({
+        "taskId": config.task_id,
+        "data": output_data,
+        "duplicateIds": corpus.duplicate_ids,
+        "rows": {
+            "total": corpus.num_rows,
+            "rejected": corpus.rejected.len(),
+            "malformedEncoding": corpus.malformed_encoding,
+            "short": short.len(),
+            "exactDuplicates": exact.num_copies(),
+        },
+        "rejectedRows": rejected_data,
+        "shortRecords": short_data,
+        "baseIndex": base_data,
+        "index": index_data,
+        "diagnostics": { "buckets": buckets, "prunedTokens": tokenizer.pruned.describe() },
+    }))
+}
+
+///
+/// The records of a job signed for indexing.
+///
+struct SignedCorpus<'a> {
+    tokenizer: Tokenizer<'a>,
+    /// Records left out of the index for having too few tokens
+    short: Vec<u32>,
+    /// Exact duplicates among the other records, whose representatives are signed
+    exact: ExactDuplicates,
+    family: Box<dyn LshFamily>,
+    /// Row-major matrices of the signatures and band hashes of the base index records and the
+    /// representatives, as returned by [`MinHashLSH::signatures`]
+    signatures: Vec<u32>,
+    band_hashes: Vec<u64>,
+}
+
+///
+/// Signs the records of the corpus, after the records of the base index if the job has one.
+///
+fn sign_corpus<'a>(
+    config: &DedupConfig,
+    corpus: &'a Corpus,
+    base: Option<&'a SnapshotHeader>,
+    base_signatures: (Vec<u32>, Vec<u64>),
+) -> Result<SignedCorpus<'a>, ServiceError> {
+    let (num_perm, num_bands) = config.lsh_params()?;
+    if let Some(header) = base {
+        header.check_options(config)?;
+    }
+    if config.embedding_column.is_some() {
+        let dimensions = corpus.embedding_dimensions()?;
+        let family = match base.map(|header| header.family.clone()) {
+            None => RandomHyperplanes::new(dimensions, num_perm, num_bands),
+            Some(FamilyParams::RandomHyperplanes(family)) if family.words() == dimensions => family,
+            Some(FamilyParams::RandomHyperplanes(family)) => {
+                return Err(ServiceError::bad_request(format!(
+                    "baseIndex holds {}-dimensional embeddings, got {dimensions}",
+                    family.words()
+                )));
+            }
+            Some(_) => {
+                return Err(ServiceError::bad_request(
+                    "baseIndex was built from texts; remove embeddingColumn",
+                ));
+            }
+        };
+        let (signatures, band_hashes) =
+            lsh::sign_vectors(&corpus.embeddings, &family, base_signatures);
+        return Ok(SignedCorpus {
+            tokenizer: Tokenizer::new(&[], PrunedTokens::default(), config.token_weighting),
+            short: Vec::new(),
+            exact: ExactDuplicates::singletons(corpus.records.len()),
+            family: Box::new(family),
+            signatures,
+            band_hashes,
+        });
+    }
+    let pruned = match base {
+        Some(header) => header.pruned_tokens(),
+        None => PrunedTokens::new(&corpus.records, config.max_document_frequency),
+    };
+    let (indexed, short) =
+        prefilter::split_short_records(&corpus.records, &pruned, config.min_tokens.unwrap_or(1));
+    let exact = ExactDuplicates::new(&corpus.records, &indexed);
+    let texts: Vec<&str> = exact
+        .representatives
+        .iter()
+        .map(|&idx| corpus.records[idx as usize].text.as_str())
+        .collect();
+    let (family, tokenizer) = match base {
+        Some(header) => {
+            let family = header.family.clone().into_token_family().ok_or_else(|| {
+                ServiceError::bad_request(
+                    "baseIndex was built from embeddings; set embeddingColumn",
+                )
+            })?;
+            (family, header.tokenizer(pruned))
+        }
+        None => {
+            let family = lsh::new_family(
+                config.signature_scheme,
+                num_perm,
+                num_bands,
+                config.signature_bits.unwrap_or(32),
+                config.token_weighting,
+            );
+            let tokenizer = Tokenizer::new(&corpus.texts(), pruned, config.token_weighting);
+            (family, tokenizer)
+        }
+    };
+    let (signatures, band_hashes) =
+        lsh::sign_texts(&texts, &tokenizer, family.as_ref(), base_signatures);
+    Ok(SignedCorpus {
+        tokenizer,
+        short,
+        exact,
+        family,
+        signatures,
+        band_hashes,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lsh::calculate_hash;
+    use lsh_dedup_service::dto::{DataFile, Record};
+
+    #[test]
+    fn document_frequencies_count_every_record() {
+        let texts = ["a b", "a b", "a  b", "a c", "x"];
+        let corpus = Corpus {
+            records: texts
+                .iter()
+                .enumerate()
+                .map(|(idx, text)| Record {
+                    id: idx.to_string(),
+                    text: text.to_string(),
+                })
+                .collect(),
+            record_sources: vec![0; texts.len()],
+            sources: vec![DataFile::default()],
+            embeddings: Vec::new(),
+            rejected: Vec::new(),
+            num_rows: texts.len(),
+            malformed_encoding: 0,
+            duplicate_ids: 0,
+        };
+        let config: DedupConfig = serde_json::from_value(json!({
+            "taskId": 7,
+            "data": { "bucket": "bucket", "key": "input/a.csv" },
+            "numPerm": 16,
+            "numBands": 4,
+            "threshold": 0.5,
+            "minTokens": 2,
+            "tokenWeighting": "tfIdf",
+        }))
+        .unwrap();
+        let signed = sign_corpus(&config, &corpus, None, Default::default()).unwrap();
+        // "x" is short and the copies of "a b" are signed once, but all five records count.
+        assert_eq!(signed.short, [4]);
+        assert_eq!(signed.exact.representatives, [0, 3]);
+        let idf = |token: &str| signed.tokenizer.idf[&calculate_hash(&token)];
+        assert_eq!(idf("a"), (1.0 + 5.0 / 4.0f64).ln());
+        assert_eq!(idf("b"), (1.0 + 5.0 / 3.0f64).ln());
+        assert_eq!(idf("x"), (1.0 + 5.0f64).ln());
+        assert_eq!(signed.tokenizer.unseen_idf, (1.0 + 5.0f64).ln());
+    }
 }
@@ -0,0 +1,145 @@
+use crate::forest::LshForest;
+use crate::lsh;
+use crate::prefilter::{self, PrunedTokens, Tokenizer};
+use crate::util::{self, Corpus};
+use lsh_dedup_service::dto::DedupConfig;
+use lsh_dedup_service::error::ServiceError;
+use rayon::prelude::*;
+use rusoto_s3::S3Client;
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+
+/// Number of neighbors listed per record unless the job sets `topK`
+const DEFAULT_TOP_K: usize = 10;
+
+///
+/// Runs a neighbors job: builds an LSH Forest over the records of `data` and lists the
+/// `top_k` most similar records of every queried record, or of every query text, whether or
+/// not they cross the threshold.
+///
+pub async fn neighbors(client: &S3Client, config: DedupConfig) -> Result<Value, ServiceError> {
+    let corpus = util::load_corpus(client, &config, &config.data).await?;
+    let output = util::resolve_output_file(&config, &corpus.sources)?;
+    let (num_perm, num_bands) = config.lsh_params()?;
+    let start = std::time::Instant::now();
+    let pruned = PrunedTokens::new(&corpus.records, config.max_document_frequency);
+    let min_tokens = config.min_tokens.unwrap_or(1);
+    let (indexed, short) = prefilter::split_short_records(&corpus.records, &pruned, min_tokens);
+    let texts: Vec<&str> = indexed
+        .iter()
+        .map(|&idx| corpus.records[idx as usize].text.as_str())
+        .collect();
+    // The forest needs every MinHash value in full, so signatures aren't packed.
+    let family = lsh::new_family(
+        config.signature_scheme,
+        num_perm,
+        num_bands,
+        32,
+        config.token_weighting,
+    );
+    let tokenizer = Tokenizer::new(&corpus.texts(), pruned, config.token_weighting);
+    let (signatures, _) = lsh::sign_texts(&texts, &tokenizer, family.as_ref(), Default::default());
+    // Query texts are signed with the tokenizer and family of the records; short ones have
+    // no neighbors.
+    let query_texts = config.queries.as_ref().map(|queries| {
+        let (signed, _) = prefilter::split_short_records(queries, &tokenizer.pruned, min_tokens);
+        let texts: Vec<&str> = signed
+            .iter()
+            .map(|&idx| queries[idx as usize].text.as_str())
+            .collect();
+        let ids: Vec<&str> = signed
+            .iter()
+            .map(|&idx| queries[idx as usize].id.as_str())
+            .collect();
+        let (signatures, _) =
+            lsh::sign_texts(&texts, &tokenizer, family.as_ref(), Default::default());
+        (ids, signatures)
+    });
+    let words = family.words();
+    let forest = LshForest::new(signatures, family);
+    let top_k = config.top_k.unwrap_or(DEFAULT_TOP_K);
+    let (query_ids, nearest) = match query_texts {
+        Some((ids, signatures)) => {
+            let nearest: Vec<Vec<(u32, f64)>> = signatures
+                .par_chunks(words)
+                .map(|signature| forest.query(signature, top_k, None))
+                .collect();
+            (ids, nearest)
+        }
+        None => {
+            let queries = record_queries(&config, &corpus, &indexed, forest.len())?;
+            let nearest: Vec<Vec<(u32, f64)>> = queries
+                .par_iter()
+                .map(|&pos| forest.query(forest.signature(pos), top_k, Some(pos)))
+                .collect();
+            let ids: Vec<&str> = queries
+                .iter()
+                .map(|&pos| corpus.records[indexed[pos as usize] as usize].id.as_str())
+                .collect();
+            (ids, nearest)
+        }
+    };
+    println!(
+        "Neighbors found in {:.4} secs",
+        (std::time::Instant::now() - start).as_secs_f64()
+    );
+    let output_data =
+        util::push_neighbor_file(client, &output, &corpus, &indexed, &query_ids, &nearest).await?;
+    let rejected_data = util::push_rejected_rows(client, &output, &corpus, "rejected").await?;
+    let short_data =
+        util::push_short_records(client, &output, &corpus, &tokenizer.pruned, &short).await?;
+    Ok(json!({
+        "taskId": config.task_id,
+        "data": output_data,
+        "duplicateIds": corpus.duplicate_ids,
+        "rows": {
+            "total": corpus.num_rows,
+            "rejected": corpus.rejected.len(),
+            "malformedEncoding": corpus.malformed_encoding,
+            "short": short.len(),
+            "queried": query_ids.len(),
+        },
+        "rejectedRows": rejected_data,
+        "shortRecords": short_data,
+        "diagnostics": { "prunedTokens": tokenizer.pruned.describe() },
+    }))
+}
+
+///
+/// Positions in the forest of the records listed in `queryIds`, or of every indexed record.
+/// Fails with a bad request if some of the ids aren't the id of any record.
+///
+fn record_queries(
+    config: &DedupConfig,
+    corpus: &Corpus,
+    indexed: &[u32],
+    num_indexed: usize,
+) -> Result<Vec<u32>, ServiceError> {
+    match &config.query_ids {
+        None => Ok((0..num_indexed as u32).collect()),
+        Some(query_ids) => {
+            let ids: HashSet<&str> = corpus.records.iter().map(|r| r.id.as_str()).collect();
+            let unknown: Vec<&str> = query_ids
+                .iter()
+                .map(String::as_str)
+                .filter(|id| !ids.contains(id))
+                .collect();
+            if !unknown.is_empty() {
+                return Err(ServiceError::bad_request(format!(
+                    "unknown queryIds: {}",
+                    unknown.join(", ")
+                )));
+            }
+            // Short records aren't in the forest and have no neighbors.
+            let positions: HashMap<&str, u32> = indexed
+                .iter()
+                .enumerate()
+                .map(|(pos, &idx)| (corpus.records[idx as usize].id.as_str(), pos as u32))
+                .collect();
+            Ok(query_ids
+                .iter()
+                .filter_map(|id| positions.get(id.as_str()).copied())
+                .collect())
+        }
+    }
+}
@@ -0,0 +1,319 @@
+use crate::lsh::calculate_hash;
+use lsh_dedup_service::dto::{Record, ShortTextPolicy, TokenWeighting};
+use rayon::prelude::*;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+///
+/// Tokens left out of the signatures because they occur in more than a given share of the
+/// records, like stopwords and template phrases that would make unrelated records collide.
+///
+#[derive(Default)]
+pub struct PrunedTokens<'a> {
+    /// Share of the records containing every pruned token
+    document_frequencies: HashMap<&'a str, f64>,
+}
+
+impl<'a> PrunedTokens<'a> {
+    ///
+    /// Counts the records containing every token and prunes the tokens whose share of
+    /// the records exceeds `max_ratio` (nothing is pruned without one).
+    ///
+    pub fn new(records: &'a [Record], max_ratio: Option<f64>) -> Self {
+        let Some(max_ratio) = max_ratio else {
+            return Self::default();
+        };
+        let counts = records
+            .par_iter()
+            .fold(HashMap::new, |mut counts: HashMap<&'a str, u32>, record| {
+                let mut tokens: Vec<&str> = record.text.split_whitespace().collect();
+                tokens.sort_unstable();
+                tokens.dedup();
+                for token in tokens {
+                    *counts.entry(token).or_default() += 1;
+                }
+                counts
+            })
+            .reduce(HashMap::new, |mut counts, other| {
+                for (token, count) in other {
+                    *counts.entry(token).or_default() += count;
+                }
+                counts
+            });
+        let num_records = records.len() as f64;
+        let document_frequencies = counts
+            .into_iter()
+            .map(|(token, count)| (token, count as f64 / num_records))
+            .filter(|&(_, ratio)| ratio > max_ratio)
+            .collect();
+        Self {
+            document_frequencies,
+        }
+    }
+
+    ///
+    /// Recreates pruned tokens from their document frequencies, as listed by `entries`.
+    ///
+    pub fn from_entries(entries: &'a [(String, f64)]) -> Self {
+        Self {
+            document_frequencies: entries
+                .iter()
+                .map(|(token, ratio)| (token.as_str(), *ratio))
+                .collect(),
+        }
+    }
+
+    ///
+    /// The pruned tokens with their document frequency, most frequent first.
+    ///
+    pub fn entries(&self) -> Vec<(&'a str, f64)> {
+        let mut pruned: Vec<(&str, f64)> = self
+            .document_frequencies
+            .iter()
+            .map(|(&token, &ratio)| (token, ratio))
+            .collect();
+        pruned.sort_unstable_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(b.0)));
+        pruned
+    }
+
+    ///
+    /// The whitespace separated tokens of `text` that weren't pruned.
+    ///
+    pub fn tokens<'t>(&'t self, text: &'t str) -> impl Iterator<Item = &'t str> + 't {
+        text.split_whitespace().filter(move |token| {
+            self.document_frequencies.is_empty() || !self.document_frequencies.contains_key(token)
+        })
+    }
+
+    ///
+    /// Lists the pruned tokens for the job diagnostics.
+    ///
+    pub fn describe(&self) -> Value {
+        self.entries()
+            .into_iter()
+            .map(|(token, ratio)| json!({ "token": token, "documentFrequency": ratio }))
+            .collect()
+    }
+}
+
+///
+/// Turns texts into the hashes of their distinct tokens along with their weights, which is
+/// what the token based LSH families sign.
+///
+pub struct Tokenizer<'a> {
+    pub pruned: PrunedTokens<'a>,
+    pub weighting: TokenWeighting,
+    /// Inverse document frequency of every token hash (empty unless weighting by TF-IDF)
+    pub idf: HashMap<u64, f64>,
+    /// Inverse document frequency of tokens that weren't counted, as if they occurred once
+    pub unseen_idf: f64,
+}
+
+impl<'a> Tokenizer<'a> {
+    ///
+    /// Creates a tokenizer leaving out `pruned` tokens. With TF-IDF weighting the document
+    /// frequencies are counted over `texts`, the texts of every record of the job (exact copies
+    /// and short records included, as for pruned tokens), smoothed as `ln(1 + N / df)` so
+    /// tokens occurring in every record keep a positive weight.
+    ///
+    pub fn new(texts: &[&str], pruned: PrunedTokens<'a>, weighting: TokenWeighting) -> Self {
+        let mut tokenizer = Self {
+            pruned,
+            weighting,
+            idf: HashMap::new(),
+            unseen_idf: (1.0 + texts.len() as f64).ln(),
+        };
+        if weighting == TokenWeighting::TfIdf {
+            tokenizer.idf = tokenizer.inverse_document_frequencies(texts);
+        }
+        tokenizer
+    }
+
+    fn inverse_document_frequencies(&self, texts: &[&str]) -> HashMap<u64, f64> {
+        let document_frequencies = texts
+            .par_iter()
+            .fold(HashMap::new, |mut frequencies: HashMap<u64, u32>, text| {
+                let mut tokens: Vec<u64> = self
+                    .pruned
+                    .tokens(text)
+                    .map(|t| calculate_hash(&t))
+                    .collect();
+                tokens.sort_unstable();
+                tokens.dedup();
+                for token in tokens {
+                    *frequencies.entry(token).or_default() += 1;
+                }
+                frequencies
+            })
+            .reduce(HashMap::new, |mut frequencies, other| {
+                for (token, count) in other {
+                    *frequencies.entry(token).or_default() += count;
+                }
+                frequencies
+            });
+        let num_texts = texts.len() as f64;
+        document_frequencies
+            .into_iter()
+            .map(|(token, df)| (token, (1.0 + num_texts / df as f64).ln()))
+            .collect()
+    }
+
+    ///
+    /// Hashes and weights of the distinct tokens of `text` that weren't pruned: one for every
+    /// token unless weighted by term frequency (and inverse document frequency).
+    ///
+    pub fn token_weights(&self, text: &str) -> Vec<(u64, f64)> {
+        let mut term_frequencies: HashMap<u64, u32> = HashMap::new();
+        for token in self.pruned.tokens(text) {
+            *term_frequencies.entry(calculate_hash(&token)).or_default() += 1;
+        }
+        term_frequencies
+            .into_iter()
+            .map(|(token, tf)| {
+                let weight = match self.weighting {
+                    TokenWeighting::None => 1.0,
+                    TokenWeighting::Tf => tf as f64,
+                    TokenWeighting::TfIdf => {
+                        tf as f64 * self.idf.get(&token).copied().unwrap_or(self.unseen_idf)
+                    }
+                };
+                (token, weight)
+            })
+            .collect()
+    }
+}
+
+///
+/// Records whose texts are identical after normalizing whitespace. Only one representative
+/// of every group has to be hashed and queried, since the copies would end up with the same
+/// signature anyway.
+///
+pub struct ExactDuplicates {
+    /// Corpus index of the first record of every group
+    pub representatives: Vec<u32>,
+    /// Corpus indices of all records of every group, starting with its representative
+    pub members: Vec<Vec<u32>>,
+}
+
+impl ExactDuplicates {
+    ///
+    /// Groups the records at `indices` by a BLAKE3 hash of their whitespace separated tokens.
+    ///
+    pub fn new(records: &[Record], indices: &[u32]) -> Self {
+        let digests: Vec<[u8; 32]> = indices
+            .par_iter()
+            .map(|&idx| content_hash(&records[idx as usize].text))
+            .collect();
+        let mut groups: HashMap<[u8; 32], usize> = HashMap::with_capacity(indices.len());
+        let mut duplicates = Self {
+            representatives: Vec::new(),
+            members: Vec::new(),
+        };
+        for (&idx, digest) in indices.iter().zip(digests) {
+            let group = *groups.entry(digest).or_insert_with(|| {
+                duplicates.representatives.push(idx);
+                duplicates.members.push(Vec::new());
+                duplicates.members.len() - 1
+            });
+            duplicates.members[group].push(idx);
+        }
+        duplicates
+    }
+
+    ///
+    /// Every one of `num_records` records on its own, for records that aren't compared by text.
+    ///
+    pub fn singletons(num_records: usize) -> Self {
+        Self {
+            representatives: (0..num_records as u32).collect(),
+            members: (0..num_records as u32).map(|idx| vec![idx]).collect(),
+        }
+    }
+
+    ///
+    /// Number of records that are a copy of their group's representative.
+    ///
+    pub fn num_copies(&self) -> usize {
+        self.members.iter().map(Vec::len).sum::<usize>() - self.representatives.len()
+    }
+}
+
+///
+/// BLAKE3 hash of the whitespace-separated tokens of a text, so texts differing only in
+/// whitespace hash the same.
+///
+pub fn content_hash(text: &str) -> [u8; 32] {
+    let mut hasher = blake3::Hasher::new();
+    for token in text.split_whitespace() {
+        hasher.update(token.as_bytes());
+        hasher.update(b" ");
+    }
+    hasher.finalize().into()
+}
+
+///
+/// Flags every record that has an identical copy in one of `groups`, so the result can tell
+/// exact matches from near duplicates.
+///
+pub fn mark_exact_matches<'a>(
+    num_records: usize,
+    groups: impl IntoIterator<Item = &'a Vec<u32>>,
+) -> Vec<bool> {
+    let mut exact = vec![false; num_records];
+    for group in groups.into_iter().filter(|group| group.len() > 1) {
+        for &idx in group {
+            exact[idx as usize] = true;
+        }
+    }
+    exact
+}
+
+///
+/// Splits the record indices into records with at least `min_tokens` tokens that weren't
+/// pruned, which are hashed, and shorter ones. Short texts would all end up with near
+/// identical signatures (empty ones with exactly the same) and collide in every band.
+/// `min_tokens` must be at least 1.
+///
+pub fn split_short_records(
+    records: &[Record],
+    pruned: &PrunedTokens,
+    min_tokens: usize,
+) -> (Vec<u32>, Vec<u32>) {
+    (0..records.len() as u32).partition(|&idx| {
+        pruned
+            .tokens(&records[idx as usize].text)
+            .nth(min_tokens - 1)
+            .is_some()
+    })
+}
+
+///
+/// Groups the short records according to `policy`. Excluded records aren't part of any group.
+///
+pub fn group_short_records(
+    records: &[Record],
+    short: &[u32],
+    policy: ShortTextPolicy,
+) -> Vec<Vec<u32>> {
+    match policy {
+        ShortTextPolicy::Exclude => Vec::new(),
+        ShortTextPolicy::ExactTokens => {
+            let mut groups: HashMap<Vec<&str>, Vec<u32>> = HashMap::new();
+            for &idx in short {
+                let tokens = records[idx as usize].text.split_whitespace().collect();
+                groups.entry(tokens).or_default().push(idx);
+            }
+            groups.into_values().collect()
+        }
+        ShortTextPolicy::ExactString => {
+            let mut groups: HashMap<&str, Vec<u32>> = HashMap::new();
+            for &idx in short {
+                groups
+                    .entry(records[idx as usize].text.as_str())
+                    .or_default()
+                    .push(idx);
+            }
+            groups.into_values().collect()
+        }
+    }
+}
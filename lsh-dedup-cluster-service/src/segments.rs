@@ -0,0 +1,199 @@
+use crate::prefilter::content_hash;
+use crate::util;
+use lsh_dedup_service::dto::{DedupConfig, SegmentUnit};
+use lsh_dedup_service::error::ServiceError;
+use rayon::prelude::*;
+use rusoto_s3::S3Client;
+use rustc_hash::FxHashSet;
+use serde_json::{json, Value};
+use std::ops::Range;
+
+///
+/// What a segments job removed from a record.
+///
+#[derive(Default)]
+pub struct SegmentStats {
+    /// Number of units of the record, blank ones included
+    pub units: usize,
+    /// Number of units removed as repeats of an earlier unit
+    pub removed_units: usize,
+    /// Number of bytes of the removed units
+    pub removed_bytes: usize,
+}
+
+/// A line or paragraph of a record.
+struct Unit {
+    /// Byte range of the unit in the text of the record
+    range: Range<usize>,
+    /// Content hash of the unit, `None` for blank units
+    hash: Option<[u8; 32]>,
+}
+
+///
+/// Runs a segments job: splits the text of every record of `data` into lines or paragraphs
+/// and removes every unit that repeats an earlier one, within the same record or across
+/// records, keeping its first occurrence in corpus order. Blank units are always kept.
+///
+pub async fn segments(client: &S3Client, config: DedupConfig) -> Result<Value, ServiceError> {
+    let corpus = util::load_corpus(client, &config, &config.data).await?;
+    let output = util::resolve_output_file(&config, &corpus.sources)?;
+    let start = std::time::Instant::now();
+    let units: Vec<Vec<Unit>> = corpus
+        .records
+        .par_iter()
+        .map(|record| units(&record.text, config.segment_unit))
+        .collect();
+    // The first occurrence of a unit is kept, so this pass follows the corpus order.
+    let mut seen: FxHashSet<[u8; 32]> = FxHashSet::default();
+    let kept: Vec<Vec<bool>> = units
+        .iter()
+        .map(|units| {
+            units
+                .iter()
+                .map(|unit| unit.hash.is_none_or(|hash| seen.insert(hash)))
+                .collect()
+        })
+        .collect();
+    let (texts, stats): (Vec<String>, Vec<SegmentStats>) = corpus
+        .records
+        .par_iter()
+        .zip(&units)
+        .zip(&kept)
+        .map(|((record, units), kept)| {
+            let mut text = String::with_capacity(record.text.len());
+            let mut stats = SegmentStats {
+                units: units.len(),
+                ..Default::default()
+            };
+            for (unit, &keep) in units.iter().zip(kept) {
+                if keep {
+                    text.push_str(&record.text[unit.range.clone()]);
+                } else {
+                    stats.removed_units += 1;
+                    stats.removed_bytes += unit.range.len();
+                }
+            }
+            (text, stats)
+        })
+        .unzip();
+    println!(
+        "Segment deduplication completed in {:.4} secs",
+        (std::time::Instant::now() - start).as_secs_f64()
+    );
+    let output_data = util::push_segment_file(client, &output, &corpus, &texts, &stats).await?;
+    let rejected_data = util::push_rejected_rows(client, &output, &corpus, "rejected").await?;
+    Ok(json!({
+        "taskId": config.task_id,
+        "data": output_data,
+        "duplicateIds": corpus.duplicate_ids,
+        "rows": {
+            "total": corpus.num_rows,
+            "rejected": corpus.rejected.len(),
+            "malformedEncoding": corpus.malformed_encoding,
+            "changed": stats.iter().filter(|stats| stats.removed_units > 0).count(),
+        },
+        "units": {
+            "total": stats.iter().map(|stats| stats.units).sum::<usize>(),
+            "removed": stats.iter().map(|stats| stats.removed_units).sum::<usize>(),
+        },
+        "bytesRemoved": stats.iter().map(|stats| stats.removed_bytes).sum::<usize>(),
+        "rejectedRows": rejected_data,
+    }))
+}
+
+/// The units of a text along with their content hashes.
+fn units(text: &str, unit: SegmentUnit) -> Vec<Unit> {
+    split_units(text, unit)
+        .into_iter()
+        .map(|range| {
+            let unit = &text[range.clone()];
+            let hash = (!unit.trim().is_empty()).then(|| content_hash(unit));
+            Unit { range, hash }
+        })
+        .collect()
+}
+
+///
+/// Splits a text into the byte ranges of its units, which cover the whole text. Every line
+/// keeps its line break, so removing a unit doesn't join its neighbors. Paragraphs are runs
+/// of non-blank lines, and every blank line between them is a unit of its own.
+///
+fn split_units(text: &str, unit: SegmentUnit) -> Vec<Range<usize>> {
+    let mut units = Vec::new();
+    let mut start = 0;
+    let mut paragraph: Option<Range<usize>> = None;
+    for line in text.split_inclusive('\n') {
+        let range = start..start + line.len();
+        start = range.end;
+        match unit {
+            SegmentUnit::Line => units.push(range),
+            SegmentUnit::Paragraph if line.trim().is_empty() => {
+                units.extend(paragraph.take());
+                units.push(range);
+            }
+            SegmentUnit::Paragraph => {
+                paragraph = Some(paragraph.map_or(range.clone(), |p| p.start..range.end));
+            }
+        }
+    }
+    units.extend(paragraph);
+    units
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The units of `text`, checking that they cover it
+    fn split(text: &str, unit: SegmentUnit) -> Vec<&str> {
+        let ranges = split_units(text, unit);
+        let mut end = 0;
+        for range in &ranges {
+            assert_eq!(range.start, end, "{text:?}");
+            end = range.end;
+        }
+        assert_eq!(end, text.len(), "{text:?}");
+        ranges.into_iter().map(|range| &text[range]).collect()
+    }
+
+    #[test]
+    fn trailing_text_without_line_break() {
+        assert_eq!(split("a\nb", SegmentUnit::Line), ["a\n", "b"]);
+        assert_eq!(split("a\nb", SegmentUnit::Paragraph), ["a\nb"]);
+        assert_eq!(split("a\n\nb", SegmentUnit::Paragraph), ["a\n", "\n", "b"]);
+        assert!(split("", SegmentUnit::Line).is_empty());
+        assert!(split("", SegmentUnit::Paragraph).is_empty());
+    }
+
+    #[test]
+    fn runs_of_blank_lines() {
+        let text = "\na\nb\n\n\n\nc\n";
+        assert_eq!(
+            split(text, SegmentUnit::Paragraph),
+            ["\n", "a\nb\n", "\n", "\n", "\n", "c\n"]
+        );
+        assert_eq!(
+            split(text, SegmentUnit::Line),
+            ["\n", "a\n", "b\n", "\n", "\n", "\n", "c\n"]
+        );
+    }
+
+    #[test]
+    fn whitespace_only_units() {
+        let text = "a\n \t\n  \r\nb";
+        assert_eq!(
+            split(text, SegmentUnit::Paragraph),
+            ["a\n", " \t\n", "  \r\n", "b"]
+        );
+        // Blank units have no hash, so they are never removed as repeats.
+        let hashed: Vec<bool> = units(text, SegmentUnit::Paragraph)
+            .iter()
+            .map(|unit| unit.hash.is_some())
+            .collect();
+        assert_eq!(hashed, [true, false, false, true]);
+        assert!(units("   ", SegmentUnit::Line)[0].hash.is_none());
+        // Units differing in whitespace only are repeats.
+        let repeats = units("a  b\na b \n", SegmentUnit::Line);
+        assert_eq!(repeats[0].hash, repeats[1].hash);
+    }
+}
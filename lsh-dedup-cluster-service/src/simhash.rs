@@ -0,0 +1,181 @@
+use crate::lsh::{band_range, mix_hash, FamilyParams, LshFamily, TokenLshFamily};
+use lsh_dedup_service::dto::SignatureScheme;
+use rand::prelude::*;
+use rustc_hash::FxHasher;
+use serde::{Deserialize, Serialize};
+use std::hash::Hasher;
+
+/// SimHash fingerprint generator (Charikar, 2002).
+///
+/// Every bit of the fingerprint is the sign of the weighted sum of that bit over the token
+/// hashes, so the share of differing bits estimates the angle between the token weight
+/// vectors. Records are compared by `1 - hamming distance / bits`, and the fingerprint is
+/// split into `num_bands` contiguous bit ranges: records within a hamming distance below
+/// `num_bands` share at least one band.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SimHash {
+    bits: u32,
+    seed: u64,
+    num_bands: usize,
+}
+
+impl SimHash {
+    /// Creates a generator of `bits` (64 or 128) wide fingerprints.
+    pub fn new(bits: u32, num_bands: usize) -> Self {
+        SimHash {
+            bits,
+            seed: StdRng::from_entropy().gen(),
+            num_bands,
+        }
+    }
+
+    /// The signature scheme of fingerprints of this width.
+    pub fn scheme(&self) -> SignatureScheme {
+        match self.bits {
+            64 => SignatureScheme::SimHash64,
+            _ => SignatureScheme::SimHash128,
+        }
+    }
+
+    /// Whether the fingerprints are 64 or 128 bits wide, split into 1 to `bits` bands.
+    pub fn is_valid(&self) -> bool {
+        matches!(self.bits, 64 | 128) && (1..=self.bits as usize).contains(&self.num_bands)
+    }
+
+    fn fingerprint(&self, tokens: &[(u64, f64)]) -> u128 {
+        let bits = self.bits as usize;
+        let mut totals = [0f64; 128];
+        for &(token_hash, weight) in tokens {
+            let low = mix_hash(token_hash ^ self.seed);
+            let hash = (mix_hash(low) as u128) << 64 | low as u128;
+            for (bit, total) in totals[..bits].iter_mut().enumerate() {
+                if hash >> bit & 1 == 1 {
+                    *total += weight;
+                } else {
+                    *total -= weight;
+                }
+            }
+        }
+        totals[..bits]
+            .iter()
+            .enumerate()
+            .filter(|(_, &total)| total > 0.0)
+            .fold(0, |fingerprint, (bit, _)| fingerprint | 1 << bit)
+    }
+
+    /// Hashes the contiguous bit ranges of `fingerprint` into `bands`.
+    fn band_hashes(&self, fingerprint: u128, bands: &mut [u64]) {
+        for (band, band_hash) in bands.iter_mut().enumerate() {
+            let range = band_range(band, self.num_bands, self.bits as usize);
+            let mut hasher = FxHasher::default();
+            hasher.write_u128(fingerprint >> range.start & u128::MAX >> (128 - range.len()));
+            *band_hash = hasher.finish();
+        }
+    }
+}
+
+impl LshFamily for SimHash {
+    fn words(&self) -> usize {
+        self.bits as usize / 32
+    }
+
+    fn num_bands(&self) -> usize {
+        self.num_bands
+    }
+
+    fn similarity(&self, signature: &[u32], other: &[u32]) -> f64 {
+        let distance: u32 = signature
+            .iter()
+            .zip(other)
+            .map(|(&a, &b)| (a ^ b).count_ones())
+            .sum();
+        1.0 - distance as f64 / self.bits as f64
+    }
+
+    fn params(&self) -> FamilyParams {
+        FamilyParams::SimHash(self.clone())
+    }
+}
+
+impl TokenLshFamily for SimHash {
+    fn sign(
+        &self,
+        tokens: &[(u64, f64)],
+        _scratch: &mut Vec<u32>,
+        signature: &mut [u32],
+        bands: &mut [u64],
+    ) {
+        let fingerprint = self.fingerprint(tokens);
+        for (word, value) in signature.iter_mut().enumerate() {
+            *value = (fingerprint >> (32 * word)) as u32;
+        }
+        self.band_hashes(fingerprint, bands);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lsh::calculate_hash;
+
+    fn simhash(bits: u32, num_bands: usize) -> SimHash {
+        SimHash {
+            bits,
+            seed: 7,
+            num_bands,
+        }
+    }
+
+    /// Unit weights of the tokens `w{start}`, ..., `w{end - 1}`
+    fn tokens(start: usize, end: usize) -> Vec<(u64, f64)> {
+        (start..end)
+            .map(|idx| (calculate_hash(&format!("w{idx}")), 1.0))
+            .collect()
+    }
+
+    fn sign(family: &SimHash, tokens: &[(u64, f64)]) -> (Vec<u32>, Vec<u64>) {
+        let mut signature = vec![0; family.words()];
+        let mut bands = vec![0; family.num_bands()];
+        family.sign(tokens, &mut Vec::new(), &mut signature, &mut bands);
+        (signature, bands)
+    }
+
+    #[test]
+    fn identical_tokens_give_identical_fingerprints() {
+        for bits in [64, 128] {
+            let family = simhash(bits, 8);
+            let signed = sign(&family, &tokens(0, 50));
+            assert_eq!(sign(&family, &tokens(0, 50)), signed);
+            assert_eq!(family.similarity(&signed.0, &signed.0), 1.0);
+        }
+    }
+
+    #[test]
+    fn unrelated_tokens_agree_on_about_half_of_the_bits() {
+        for bits in [64, 128] {
+            let family = simhash(bits, 8);
+            let (a, _) = sign(&family, &tokens(0, 200));
+            let (b, _) = sign(&family, &tokens(1000, 1200));
+            let similarity = family.similarity(&a, &b);
+            assert!((similarity - 0.5).abs() < 0.15, "{bits} bits: {similarity}");
+        }
+    }
+
+    #[test]
+    fn bands_cover_every_bit_once() {
+        for bits in [64, 128] {
+            for num_bands in [1, 3, 7, 8, 10, bits as usize] {
+                let family = simhash(bits, num_bands);
+                let mut bands = vec![0; num_bands];
+                family.band_hashes(0, &mut bands);
+                for bit in 0..bits {
+                    let mut flipped = vec![0; num_bands];
+                    family.band_hashes(1 << bit, &mut flipped);
+                    let differing = bands.iter().zip(&flipped).filter(|(a, b)| a != b).count();
+                    assert_eq!(differing, 1, "bit {bit} of {bits}, {num_bands} bands");
+                }
+            }
+        }
+    }
+}
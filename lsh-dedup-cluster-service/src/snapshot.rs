@@ -0,0 +1,411 @@
+use crate::lsh::{FamilyParams, MinHashLSH};
+use crate::prefilter::{PrunedTokens, Tokenizer};
+use crate::util::Corpus;
+use lsh_dedup_service::dto::{DataFile, DedupConfig, TokenWeighting};
+use lsh_dedup_service::error::ServiceError;
+use lsh_dedup_service::util::{download_object_from_s3, upload_object_to_s3};
+use rusoto_s3::S3Client;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Version of the snapshot layout, bumped whenever older snapshots can't be read anymore
+pub const SCHEMA_VERSION: u32 = 2;
+/// Leading bytes of every snapshot
+const MAGIC: &[u8; 8] = b"LSHINDEX";
+
+///
+/// Everything about an index snapshot except the signature matrices: the parameters records
+/// have to be tokenized and signed with to be comparable, and the indexed records.
+///
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SnapshotHeader {
+    pub schema_version: u32,
+    pub task_id: usize,
+    /// Unix seconds
+    pub created_at: u64,
+    pub family: FamilyParams,
+    pub weighting: TokenWeighting,
+    pub idf: Vec<(u64, f64)>,
+    pub unseen_idf: f64,
+    pub pruned_tokens: Vec<(String, f64)>,
+    #[serde(flatten)]
+    pub records: IndexedRecords,
+}
+
+///
+/// The records of an index, in the order of its signatures.
+///
+#[derive(Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IndexedRecords {
+    /// Id of every indexed record
+    pub ids: Vec<String>,
+    /// `s3://` URIs of the files the records were read from
+    pub sources: Vec<String>,
+    /// Index into `sources` of every record
+    pub record_sources: Vec<u32>,
+    /// Cluster label of every record, kept by the jobs extending the index
+    pub clusters: Vec<u32>,
+}
+
+impl IndexedRecords {
+    ///
+    /// Appends the records of a job with their cluster labels. `indexed` maps the record
+    /// indices of the LSH to the corpus.
+    ///
+    pub fn extend(&mut self, corpus: &Corpus, indexed: &[u32], clusters: &[u32]) {
+        let first_source = self.sources.len() as u32;
+        self.sources.extend(
+            corpus
+                .sources
+                .iter()
+                .map(|data| format!("s3://{}/{}", data.bucket, data.key)),
+        );
+        for &idx in indexed {
+            self.ids.push(corpus.records[idx as usize].id.clone());
+            self.record_sources
+                .push(first_source + corpus.record_sources[idx as usize] as u32);
+        }
+        self.clusters.extend_from_slice(clusters);
+    }
+}
+
+impl SnapshotHeader {
+    ///
+    /// The tokens pruned when the indexed records were signed.
+    ///
+    pub fn pruned_tokens(&self) -> PrunedTokens<'_> {
+        PrunedTokens::from_entries(&self.pruned_tokens)
+    }
+
+    ///
+    /// Fails unless the LSH options of the job match the ones the indexed records were
+    /// signed with.
+    ///
+    pub fn check_options(&self, config: &DedupConfig) -> Result<(), ServiceError> {
+        let (num_perm, num_bands) = config.lsh_params()?;
+        let mismatched = self
+            .family
+            .mismatched_option(
+                num_perm,
+                num_bands,
+                config.signature_scheme,
+                config.signature_bits,
+            )
+            .or((self.weighting != config.token_weighting).then_some("tokenWeighting"));
+        match mismatched {
+            Some(option) => Err(ServiceError::bad_request(format!(
+                "{option} differs from the one baseIndex was built with"
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    ///
+    /// Recreates the tokenizer the indexed records were signed with, given their
+    /// [`SnapshotHeader::pruned_tokens`].
+    ///
+    pub fn tokenizer<'a>(&self, pruned: PrunedTokens<'a>) -> Tokenizer<'a> {
+        Tokenizer {
+            pruned,
+            weighting: self.weighting,
+            idf: self.idf.iter().copied().collect(),
+            unseen_idf: self.unseen_idf,
+        }
+    }
+}
+
+///
+/// An index loaded back from object storage.
+///
+/// Snapshots are laid out as the magic bytes `LSHINDEX`, the schema version (u32), the length
+/// of the JSON encoded [`SnapshotHeader`] (u64) and the header itself, followed by the
+/// row-major signature (u32) and band hash (u64) matrices. Numbers are little endian. The band
+/// tables are rebuilt from the band hashes when loading.
+///
+pub struct IndexSnapshot {
+    pub header: SnapshotHeader,
+    pub signatures: Vec<u32>,
+    pub band_hashes: Vec<u64>,
+}
+
+impl IndexSnapshot {
+    ///
+    /// Parses a snapshot, rejecting other schema versions, truncated snapshots and headers
+    /// that don't describe a usable index.
+    ///
+    pub fn decode(bytes: &[u8]) -> Result<Self, ServiceError> {
+        let invalid = || ServiceError::bad_request("not an index snapshot");
+        let rest = bytes.strip_prefix(MAGIC).ok_or_else(invalid)?;
+        let (version, rest) = rest.split_first_chunk::<4>().ok_or_else(invalid)?;
+        let version = u32::from_le_bytes(*version);
+        if version != SCHEMA_VERSION {
+            return Err(ServiceError::bad_request(format!(
+                "index snapshot has schema version {version}, expected {SCHEMA_VERSION}"
+            )));
+        }
+        let (header_len, rest) = rest.split_first_chunk::<8>().ok_or_else(invalid)?;
+        let header_len = usize::try_from(u64::from_le_bytes(*header_len)).map_err(|_| invalid())?;
+        if rest.len() < header_len {
+            return Err(invalid());
+        }
+        let (header, rest) = rest.split_at(header_len);
+        let header: SnapshotHeader =
+            serde_json::from_slice(header).map_err(ServiceError::bad_request)?;
+        if !header.family.is_valid() {
+            return Err(ServiceError::bad_request(
+                "index snapshot holds invalid LSH parameters",
+            ));
+        }
+        let family = header.family.clone().into_family();
+        let records = &header.records;
+        let num_records = records.ids.len();
+        if records.record_sources.len() != num_records
+            || records.clusters.len() != num_records
+            || records
+                .record_sources
+                .iter()
+                .any(|&source| source as usize >= records.sources.len())
+        {
+            return Err(ServiceError::bad_request(
+                "index snapshot lists inconsistent records",
+            ));
+        }
+        let truncated = || ServiceError::bad_request("index snapshot is truncated or corrupted");
+        let signature_bytes = num_records
+            .checked_mul(family.words())
+            .and_then(|words| words.checked_mul(4))
+            .ok_or_else(truncated)?;
+        let band_bytes = num_records
+            .checked_mul(family.num_bands())
+            .and_then(|bands| bands.checked_mul(8))
+            .ok_or_else(truncated)?;
+        if signature_bytes.checked_add(band_bytes) != Some(rest.len()) {
+            return Err(truncated());
+        }
+        let (signatures, band_hashes) = rest.split_at(signature_bytes);
+        Ok(IndexSnapshot {
+            header,
+            signatures: signatures
+                .chunks_exact(4)
+                .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+                .collect(),
+            band_hashes: band_hashes
+                .chunks_exact(8)
+                .map(|hash| u64::from_le_bytes(hash.try_into().unwrap()))
+                .collect(),
+        })
+    }
+}
+
+///
+/// Serializes the index of a job, whose signatures belong to `records`.
+///
+pub fn encode_snapshot(
+    task_id: usize,
+    lsh: &MinHashLSH,
+    tokenizer: &Tokenizer,
+    records: IndexedRecords,
+) -> Result<Vec<u8>, ServiceError> {
+    let header = SnapshotHeader {
+        schema_version: SCHEMA_VERSION,
+        task_id,
+        created_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(ServiceError::internal_server_error)?
+            .as_secs(),
+        family: lsh.family().params(),
+        weighting: tokenizer.weighting,
+        idf: tokenizer
+            .idf
+            .iter()
+            .map(|(&token, &idf)| (token, idf))
+            .collect(),
+        unseen_idf: tokenizer.unseen_idf,
+        pruned_tokens: tokenizer
+            .pruned
+            .entries()
+            .into_iter()
+            .map(|(token, ratio)| (token.to_string(), ratio))
+            .collect(),
+        records,
+    };
+    let header = serde_json::to_vec(&header).map_err(ServiceError::internal_server_error)?;
+    let (signatures, band_hashes) = lsh.signatures();
+    let mut bytes = Vec::with_capacity(
+        MAGIC.len() + 12 + header.len() + signatures.len() * 4 + band_hashes.len() * 8,
+    );
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&SCHEMA_VERSION.to_le_bytes());
+    bytes.extend_from_slice(&(header.len() as u64).to_le_bytes());
+    bytes.extend_from_slice(&header);
+    bytes.extend(signatures.iter().flat_map(|word| word.to_le_bytes()));
+    bytes.extend(band_hashes.iter().flat_map(|hash| hash.to_le_bytes()));
+    Ok(bytes)
+}
+
+///
+/// Writes a snapshot of the index of a job, returning its location.
+///
+pub async fn push_index_snapshot(
+    client: &S3Client,
+    target: &DataFile,
+    snapshot: Vec<u8>,
+    num_records: usize,
+) -> Result<Value, ServiceError> {
+    upload_object_to_s3(client, snapshot, target.bucket.clone(), target.key.clone()).await?;
+    Ok(json!({
+        "bucket": target.bucket,
+        "key": target.key,
+        "schemaVersion": SCHEMA_VERSION,
+        "records": num_records,
+    }))
+}
+
+///
+/// Loads an index snapshot written by an earlier job.
+///
+pub async fn pull_index_snapshot(
+    client: &S3Client,
+    source: &DataFile,
+) -> Result<IndexSnapshot, ServiceError> {
+    let bytes = download_object_from_s3(client, source.bucket.clone(), source.key.clone()).await?;
+    IndexSnapshot::decode(&bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lsh;
+    use lsh_dedup_service::dto::SignatureScheme;
+    use lsh_dedup_service::response::Status;
+
+    /// A snapshot of an index over three texts
+    fn snapshot() -> Vec<u8> {
+        let texts = ["a b c d", "a b c e", "x y z"];
+        let tokenizer = Tokenizer::new(&texts, PrunedTokens::default(), TokenWeighting::None);
+        let family = lsh::new_family(SignatureScheme::MinHash, 16, 4, 8, TokenWeighting::None);
+        let lsh = MinHashLSH::new(&texts, &tokenizer, family, Default::default());
+        let records = IndexedRecords {
+            ids: vec!["1".into(), "2".into(), "3".into()],
+            sources: vec!["s3://bucket/input/a.csv".into()],
+            record_sources: vec![0; 3],
+            clusters: vec![0, 0, 1],
+        };
+        encode_snapshot(7, &lsh, &tokenizer, records).unwrap()
+    }
+
+    /// The snapshot with its JSON header edited
+    fn edit_header(bytes: &[u8], edit: impl FnOnce(&mut Value)) -> Vec<u8> {
+        let header_len = u64::from_le_bytes(bytes[12..20].try_into().unwrap()) as usize;
+        let mut header: Value = serde_json::from_slice(&bytes[20..20 + header_len]).unwrap();
+        edit(&mut header);
+        let header = serde_json::to_vec(&header).unwrap();
+        let mut edited = bytes[..12].to_vec();
+        edited.extend_from_slice(&(header.len() as u64).to_le_bytes());
+        edited.extend_from_slice(&header);
+        edited.extend_from_slice(&bytes[20 + header_len..]);
+        edited
+    }
+
+    fn assert_rejected(bytes: &[u8]) {
+        match IndexSnapshot::decode(bytes) {
+            Ok(_) => panic!("corrupt snapshot was decoded"),
+            Err(err) => assert!(matches!(err.status, Status::BadRequest), "{}", err.msg),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let decoded = IndexSnapshot::decode(&snapshot()).unwrap();
+        assert_eq!(decoded.header.task_id, 7);
+        assert_eq!(decoded.header.records.ids, ["1", "2", "3"]);
+        // 16 values of 8 bits fill 4 words.
+        assert_eq!(decoded.signatures.len(), 3 * 4);
+        assert_eq!(decoded.band_hashes.len(), 3 * 4);
+    }
+
+    #[test]
+    fn truncated_snapshots_are_rejected() {
+        let bytes = snapshot();
+        for len in [0, 8, 12, 20, 40, bytes.len() - 1] {
+            assert_rejected(&bytes[..len]);
+        }
+        let mut longer = bytes.clone();
+        longer.push(0);
+        assert_rejected(&longer);
+        let mut huge_header = bytes.clone();
+        huge_header[12..20].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert_rejected(&huge_header);
+    }
+
+    #[test]
+    fn invalid_headers_are_rejected() {
+        let bytes = snapshot();
+        for (field, value) in [
+            ("numBands", json!(0)),
+            ("numBands", json!(17)),
+            ("numPerm", json!(0)),
+            ("numPerm", json!(usize::MAX)),
+            ("permutations", json!([])),
+            ("scheme", json!("simHash64")),
+        ] {
+            assert_rejected(&edit_header(&bytes, |header| {
+                header["family"][field] = value;
+            }));
+        }
+        for bits in [0, 33] {
+            assert_rejected(&edit_header(&bytes, |header| {
+                header["family"]["packing"]["bits"] = json!(bits);
+            }));
+        }
+        assert_rejected(&edit_header(&bytes, |header| {
+            header["family"]["packing"]["words"] = json!(usize::MAX);
+        }));
+        assert_rejected(&edit_header(&bytes, |header| {
+            header["family"] = json!({ "type": "simHash", "bits": 64, "seed": 1, "numBands": 0 });
+        }));
+        assert_rejected(&edit_header(&bytes, |header| {
+            header["recordSources"] = json!([0, 0, 1]);
+        }));
+        assert_rejected(&edit_header(&bytes, |header| {
+            header["clusters"] = json!([0, 0]);
+        }));
+    }
+
+    #[test]
+    fn job_options_must_match_the_snapshot() {
+        let decoded = IndexSnapshot::decode(&snapshot()).unwrap();
+        let job = |options: Value| -> DedupConfig {
+            let mut config = json!({
+                "taskId": 8,
+                "data": { "bucket": "bucket", "key": "input/b.csv" },
+                "numPerm": 16,
+                "numBands": 4,
+                "threshold": 0.5,
+                "signatureBits": 8,
+            });
+            for (key, value) in options.as_object().unwrap() {
+                config[key] = value.clone();
+            }
+            serde_json::from_value(config).unwrap()
+        };
+        decoded.header.check_options(&job(json!({}))).unwrap();
+        for (options, option) in [
+            (json!({ "numPerm": 32 }), "numPerm"),
+            (json!({ "numBands": 8 }), "numBands"),
+            (json!({ "signatureBits": 16 }), "signatureBits"),
+            (
+                json!({ "signatureScheme": "onePermutation" }),
+                "signatureScheme",
+            ),
+            (json!({ "tokenWeighting": "tf" }), "tokenWeighting"),
+        ] {
+            let err = decoded.header.check_options(&job(options)).unwrap_err();
+            assert!(matches!(err.status, Status::BadRequest));
+            assert!(err.msg.starts_with(option), "{}", err.msg);
+        }
+    }
+}
@@ -0,0 +1,444 @@
+use crate::util;
+use lsh_dedup_service::dto::{DedupConfig, Record, SpanPolicy};
+use lsh_dedup_service::error::ServiceError;
+use rayon::prelude::*;
+use rusoto_s3::S3Client;
+use serde_json::{json, Value};
+
+/// Shortest repeated span, in bytes, unless the job sets `minSpanBytes`
+const DEFAULT_MIN_SPAN_BYTES: usize = 100;
+/// Byte appended to every text in the suffix array, which never occurs in UTF-8
+const SEPARATOR: u8 = 0xFF;
+
+///
+/// A span of a record repeating text found earlier in the corpus.
+///
+pub struct Span {
+    /// Index of the record in the corpus
+    pub record: u32,
+    /// Byte offset of the span in the text of the record
+    pub start: usize,
+    /// Byte offset of the end of the span (exclusive)
+    pub end: usize,
+    /// Index of the record holding the earlier occurrence of the start of the span
+    pub matched_record: u32,
+    /// Byte offset of that occurrence in the text of the matched record
+    pub matched_start: usize,
+}
+
+///
+/// Suffix array over the texts of a corpus, each followed by a [`SEPARATOR`], with the
+/// longest common prefix of every pair of adjacent suffixes. Common prefixes stop at the
+/// separators, so no match spans two records.
+///
+pub struct SuffixArray {
+    text: Vec<u8>,
+    /// Offset of the text of every record in `text`
+    starts: Vec<usize>,
+    /// Offsets of the suffixes of `text`, in lexicographic order
+    suffixes: Vec<u32>,
+    /// Longest common prefix of every suffix with the previous one (0 for the first)
+    lcp: Vec<u32>,
+}
+
+impl SuffixArray {
+    ///
+    /// Sorts the suffixes of the concatenated texts of `records` by prefix doubling, then
+    /// computes the common prefixes with Kasai's algorithm. Fails when the texts don't fit
+    /// 32-bit offsets.
+    ///
+    pub fn new(records: &[Record]) -> Result<Self, ServiceError> {
+        let len: usize = records.iter().map(|record| record.text.len() + 1).sum();
+        if len >= u32::MAX as usize {
+            return Err(ServiceError::bad_request(
+                "substrings jobs support up to 4 GiB of text",
+            ));
+        }
+        let mut text = Vec::with_capacity(len);
+        let mut starts = Vec::with_capacity(records.len());
+        for record in records {
+            starts.push(text.len());
+            text.extend_from_slice(record.text.as_bytes());
+            text.push(SEPARATOR);
+        }
+        let suffixes = sort_suffixes(&text);
+        let lcp = common_prefixes(&text, &suffixes);
+        Ok(SuffixArray {
+            text,
+            starts,
+            suffixes,
+            lcp,
+        })
+    }
+
+    ///
+    /// Finds, for every offset of the text, the longest prefix of its suffix that also
+    /// starts at an earlier offset, as `(length, earlier offset)`. The best earlier suffix is
+    /// the nearest one with a smaller offset in sorted order, on either side.
+    ///
+    fn earlier_matches(&self) -> Vec<(u32, u32)> {
+        let mut matches = vec![(0, 0); self.text.len()];
+        let len = self.suffixes.len();
+        nearest_earlier(
+            &mut matches,
+            (0..len).map(|rank| (self.suffixes[rank], self.lcp[rank])),
+        );
+        // Scanning backwards, the common prefix with the previous suffix is that of the next.
+        nearest_earlier(
+            &mut matches,
+            (0..len).rev().map(|rank| {
+                (
+                    self.suffixes[rank],
+                    self.lcp.get(rank + 1).copied().unwrap_or(0),
+                )
+            }),
+        );
+        matches
+    }
+
+    ///
+    /// Finds the spans of at least `min_len` bytes repeating earlier text, whether in an
+    /// earlier record or earlier in the same record, merged where they overlap and shrunk
+    /// to character boundaries. The first occurrence of repeated text is never part of a
+    /// span.
+    ///
+    pub fn repeated_spans(&self, records: &[Record], min_len: usize) -> Vec<Span> {
+        let matches = self.earlier_matches();
+        let record_of = |offset: usize| self.starts.partition_point(|&start| start <= offset) - 1;
+        records
+            .par_iter()
+            .enumerate()
+            .flat_map_iter(|(idx, record)| {
+                let start = self.starts[idx];
+                let text = &record.text;
+                let mut spans: Vec<Span> = Vec::new();
+                let mut current: Option<Span> = None;
+                for offset in 0..text.len() {
+                    let (len, earlier) = matches[start + offset];
+                    let len = len as usize;
+                    if len < min_len {
+                        continue;
+                    }
+                    let end = offset + len;
+                    match &mut current {
+                        Some(span) if offset <= span.end => span.end = span.end.max(end),
+                        _ => {
+                            let matched = record_of(earlier as usize);
+                            spans.extend(current.take());
+                            current = Some(Span {
+                                record: idx as u32,
+                                start: offset,
+                                end,
+                                matched_record: matched as u32,
+                                matched_start: earlier as usize - self.starts[matched],
+                            });
+                        }
+                    }
+                }
+                spans.extend(current);
+                spans.into_iter().filter_map(move |mut span| {
+                    while !text.is_char_boundary(span.start) {
+                        span.start += 1;
+                        span.matched_start += 1;
+                    }
+                    while !text.is_char_boundary(span.end) {
+                        span.end -= 1;
+                    }
+                    (span.end >= span.start + min_len).then_some(span)
+                })
+            })
+            .collect()
+    }
+}
+
+///
+/// Runs a substrings job: builds a suffix array over the texts of `data` and finds the spans
+/// of at least `min_span_bytes` bytes repeating earlier text, which the job lists or excises
+/// from the records.
+///
+pub async fn substrings(client: &S3Client, config: DedupConfig) -> Result<Value, ServiceError> {
+    let corpus = util::load_corpus(client, &config, &config.data).await?;
+    let output = util::resolve_output_file(&config, &corpus.sources)?;
+    let start = std::time::Instant::now();
+    let suffix_array = SuffixArray::new(&corpus.records)?;
+    println!(
+        "Suffix array built in {:.4} secs",
+        (std::time::Instant::now() - start).as_secs_f64()
+    );
+    let start = std::time::Instant::now();
+    let min_len = config.min_span_bytes.unwrap_or(DEFAULT_MIN_SPAN_BYTES);
+    let spans = suffix_array.repeated_spans(&corpus.records, min_len);
+    drop(suffix_array);
+    println!(
+        "Repeated spans found in {:.4} secs",
+        (std::time::Instant::now() - start).as_secs_f64()
+    );
+    let (output_data, spans_data) = match config.span_policy {
+        SpanPolicy::Report => (
+            util::push_span_file(client, &output, &corpus, &spans).await?,
+            None,
+        ),
+        SpanPolicy::Excise => {
+            let texts = excise(&corpus.records, &spans);
+            let spans_file = util::sidecar_file(&output, "spans");
+            (
+                util::push_text_file(client, &output, &corpus, &texts).await?,
+                Some(util::push_span_file(client, &spans_file, &corpus, &spans).await?),
+            )
+        }
+    };
+    let rejected_data = util::push_rejected_rows(client, &output, &corpus, "rejected").await?;
+    let mut changed: Vec<u32> = spans.iter().map(|span| span.record).collect();
+    changed.dedup();
+    Ok(json!({
+        "taskId": config.task_id,
+        "data": output_data,
+        "spans": spans_data,
+        "duplicateIds": corpus.duplicate_ids,
+        "rows": {
+            "total": corpus.num_rows,
+            "rejected": corpus.rejected.len(),
+            "malformedEncoding": corpus.malformed_encoding,
+            "changed": changed.len(),
+        },
+        "repeatedSpans": spans.len(),
+        "repeatedBytes": spans.iter().map(|span| span.end - span.start).sum::<usize>(),
+        "rejectedRows": rejected_data,
+    }))
+}
+
+/// The texts of `records` without their `spans`, which are sorted by record and offset.
+fn excise(records: &[Record], spans: &[Span]) -> Vec<String> {
+    let mut spans = spans.iter().peekable();
+    records
+        .iter()
+        .enumerate()
+        .map(|(idx, record)| {
+            let mut text = String::with_capacity(record.text.len());
+            let mut kept_from = 0;
+            while let Some(span) = spans.next_if(|span| span.record == idx as u32) {
+                text.push_str(&record.text[kept_from..span.start]);
+                kept_from = span.end;
+            }
+            text.push_str(&record.text[kept_from..]);
+            text
+        })
+        .collect()
+}
+
+///
+/// Updates `matches` with the nearest suffix of smaller offset scanned before every suffix,
+/// given the suffixes in sorted order (or reversed) with their common prefix with the one
+/// scanned before. A stack holds the candidates by increasing offset, each with the smallest
+/// common prefix between it and the candidate above it (or the current suffix for the top):
+/// a candidate with a larger offset than the current suffix can't be the best for any later
+/// one, whose common prefix with the current suffix is at least as long.
+///
+fn nearest_earlier(matches: &mut [(u32, u32)], scan: impl Iterator<Item = (u32, u32)>) {
+    let mut stack: Vec<(u32, u32)> = Vec::new();
+    for (pos, lcp) in scan {
+        if let Some(top) = stack.last_mut() {
+            top.1 = top.1.min(lcp);
+        }
+        while stack.last().is_some_and(|&(top, _)| top > pos) {
+            let (_, popped) = stack.pop().unwrap();
+            if let Some(top) = stack.last_mut() {
+                top.1 = top.1.min(popped);
+            }
+        }
+        if let Some(&(earlier, len)) = stack.last() {
+            if len > matches[pos as usize].0 {
+                matches[pos as usize] = (len, earlier);
+            }
+        }
+        stack.push((pos, u32::MAX));
+    }
+}
+
+///
+/// Sorts the suffixes of `text` by prefix doubling: every round sorts them by the ranks of
+/// their first `k` bytes and of the `k` following ones, until all ranks differ.
+///
+fn sort_suffixes(text: &[u8]) -> Vec<u32> {
+    let len = text.len();
+    let mut suffixes: Vec<u32> = (0..len as u32).collect();
+    // Rank 0 stands for the end of the text, so shorter suffixes sort first.
+    let mut ranks: Vec<u32> = text.iter().map(|&byte| byte as u32 + 1).collect();
+    let mut keys: Vec<u64> = vec![0; len];
+    let mut k = 1;
+    loop {
+        keys.par_iter_mut().enumerate().for_each(|(pos, key)| {
+            let next = ranks.get(pos + k).copied().unwrap_or(0);
+            *key = ((ranks[pos] as u64) << 32) | next as u64;
+        });
+        suffixes.par_sort_unstable_by_key(|&pos| keys[pos as usize]);
+        let mut rank = 0;
+        for (i, &pos) in suffixes.iter().enumerate() {
+            if i == 0 || keys[pos as usize] != keys[suffixes[i - 1] as usize] {
+                rank += 1;
+            }
+            ranks[pos as usize] = rank;
+        }
+        if rank as usize == len {
+            return suffixes;
+        }
+        k *= 2;
+    }
+}
+
+///
+/// Kasai's algorithm: the longest common prefix of every suffix with the previous one in
+/// sorted order, stopping at separators.
+///
+fn common_prefixes(text: &[u8], suffixes: &[u32]) -> Vec<u32> {
+    let len = text.len();
+    let mut ranks = vec![0u32; len];
+    for (rank, &pos) in suffixes.iter().enumerate() {
+        ranks[pos as usize] = rank as u32;
+    }
+    let mut lcp = vec![0u32; len];
+    let mut matched = 0;
+    for pos in 0..len {
+        let rank = ranks[pos] as usize;
+        if rank == 0 {
+            matched = 0;
+            continue;
+        }
+        let previous = suffixes[rank - 1] as usize;
+        while pos + matched < len
+            && previous + matched < len
+            && text[pos + matched] == text[previous + matched]
+            && text[pos + matched] != SEPARATOR
+        {
+            matched += 1;
+        }
+        lcp[rank] = matched as u32;
+        matched = matched.saturating_sub(1);
+    }
+    lcp
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records(texts: &[&str]) -> Vec<Record> {
+        texts
+            .iter()
+            .enumerate()
+            .map(|(idx, text)| Record {
+                id: idx.to_string(),
+                text: text.to_string(),
+            })
+            .collect()
+    }
+
+    /// The spans found in `texts`, as `(record, start, end, matched record, matched start)`
+    fn spans(texts: &[&str], min_len: usize) -> Vec<(u32, usize, usize, u32, usize)> {
+        let records = records(texts);
+        SuffixArray::new(&records)
+            .unwrap()
+            .repeated_spans(&records, min_len)
+            .into_iter()
+            .map(|span| {
+                let (start, end) = (span.start, span.end);
+                (
+                    span.record,
+                    start,
+                    end,
+                    span.matched_record,
+                    span.matched_start,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn banana() {
+        assert_eq!(spans(&["banana"], 1), [(0, 3, 6, 0, 1)]);
+        assert_eq!(spans(&["banana"], 3), [(0, 3, 6, 0, 1)]);
+        assert_eq!(spans(&["banana"], 4), []);
+    }
+
+    #[test]
+    fn abracadabra() {
+        let records = records(&["abracadabra"]);
+        let matches = SuffixArray::new(&records).unwrap().earlier_matches();
+        assert_eq!(matches[7], (4, 0));
+        assert_eq!(matches[3], (1, 0));
+        assert_eq!(spans(&["abracadabra"], 2), [(0, 7, 11, 0, 0)]);
+    }
+
+    #[test]
+    fn earlier_matches_are_the_longest() {
+        let texts = [
+            "mississippi",
+            "sip",
+            "pississim",
+            "",
+            "ssissi",
+            "ippi mississippi",
+        ];
+        let records = records(&texts);
+        let suffix_array = SuffixArray::new(&records).unwrap();
+        let text = &suffix_array.text;
+        let common = |a: usize, b: usize| {
+            (0..)
+                .take_while(|&i| {
+                    a + i < text.len() && text[a + i] == text[b + i] && text[a + i] != SEPARATOR
+                })
+                .count()
+        };
+        for (offset, &(len, earlier)) in suffix_array.earlier_matches().iter().enumerate() {
+            let longest = (0..offset).map(|e| common(offset, e)).max().unwrap_or(0);
+            assert_eq!(len as usize, longest, "offset {offset}");
+            if len > 0 {
+                assert!((earlier as usize) < offset);
+                assert_eq!(common(offset, earlier as usize), longest);
+            }
+        }
+    }
+
+    #[test]
+    fn spans_end_with_the_text() {
+        assert_eq!(
+            spans(&["hello world", "say hello world"], 5),
+            [(1, 4, 15, 0, 0)]
+        );
+        // The match stops at the end of the first record instead of running into the next.
+        assert_eq!(spans(&["abcd", "abcde"], 3), [(1, 0, 4, 0, 0)]);
+    }
+
+    #[test]
+    fn overlapping_repeats_are_merged() {
+        assert_eq!(spans(&["abcabcabc"], 3), [(0, 3, 9, 0, 0)]);
+        assert_eq!(
+            spans(&["xyz123", "123abc", "xyz123abc"], 3),
+            [(1, 0, 3, 0, 3), (2, 0, 9, 0, 0)]
+        );
+    }
+
+    #[test]
+    fn spans_are_shrunk_to_char_boundaries() {
+        // 'é' and 'ǩ' share their second byte, so the match starts inside a character.
+        assert_eq!(spans(&["éxyz", "ǩxyz"], 3), [(1, 2, 5, 0, 2)]);
+        assert_eq!(spans(&["éxy", "ǩxy"], 3), []);
+    }
+
+    #[test]
+    fn empty_input() {
+        assert_eq!(spans(&[], 1), []);
+        assert_eq!(spans(&["", "", ""], 1), []);
+        assert_eq!(excise(&[], &[]), Vec::<String>::new());
+    }
+
+    #[test]
+    fn excise_removes_spans() {
+        let texts = ["xyz123", "123abc", "xyz123abc and abc"];
+        let records = records(&texts);
+        let spans = SuffixArray::new(&records)
+            .unwrap()
+            .repeated_spans(&records, 3);
+        assert_eq!(excise(&records, &spans), ["xyz123", "abc", " and "]);
+    }
+}
@@ -1,59 +1,1675 @@
-use crate::dedup::DeduplicationTable;
-use csv::{Reader, Writer};
-use lsh_dedup_service::dto::{DataFile, Record};
+use crate::decontaminate;
+use crate::lsh::MinHashLSH;
+use crate::prefilter::PrunedTokens;
+use crate::segments::SegmentStats;
+use crate::substrings::Span;
+use bytes::Bytes;
+use csv::{ByteRecord, ReaderBuilder, StringRecord, WriterBuilder};
+use encoding_rs::{Encoding, UTF_8};
+use futures::stream::{self, StreamExt, TryStreamExt};
+use lsh_dedup_service::dto::{
+    ContaminationPolicy, CsvDialect, DataFile, DataSource, DedupConfig, DuplicateIdPolicy, Record,
+};
 use lsh_dedup_service::error::ServiceError;
-use lsh_dedup_service::util::{download_object_from_s3, upload_object_to_s3};
+use lsh_dedup_service::util::{download_object_from_s3, list_objects_in_s3, upload_object_to_s3};
+use parquet::file::reader::{FileReader, SerializedFileReader};
+use parquet::record::Field;
 use rusoto_s3::S3Client;
 use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::iter;
+use std::time::{SystemTime, UNIX_EPOCH};
 
+/// Maximum number of input files downloaded at the same time
+const MAX_CONCURRENT_DOWNLOADS: usize = 8;
+/// Number of largest band buckets per band reported in the job diagnostics
+const REPORTED_BUCKETS_PER_BAND: usize = 3;
+/// Number of record ids listed for each reported band bucket
+const REPORTED_BUCKET_IDS: usize = 5;
+/// UTF-8 encoding of U+FFFD
+const REPLACEMENT_CHARACTER: &[u8] = "\u{FFFD}".as_bytes();
+/// Names of the sidecar objects jobs may write next to their result file
+const SIDECAR_NAMES: [&str; 4] = ["rejected", "reference.rejected", "short", "spans"];
+
+///
+/// The records of every input file of a job.
+///
+pub struct Corpus {
+    pub records: Vec<Record>,
+    /// Index into `sources` of the file each record was read from
+    pub record_sources: Vec<usize>,
+    pub sources: Vec<DataFile>,
+    /// Embedding of every record (empty unless the job reads an embedding column)
+    pub embeddings: Vec<Vec<f32>>,
+    /// Rows that couldn't be read, along with the index of their source file
+    pub rejected: Vec<(usize, RejectedRow)>,
+    /// Number of rows read, rejected ones included
+    pub num_rows: usize,
+    /// Number of rows holding byte sequences that were malformed in the encoding of their file
+    pub malformed_encoding: usize,
+    /// Number of ids that were shared by more than one record
+    pub duplicate_ids: usize,
+}
+
+///
+/// The rows read from a single input file.
+///
+#[derive(Default)]
+pub struct FileRows {
+    pub records: Vec<Record>,
+    pub embeddings: Vec<Vec<f32>>,
+    pub rejected: Vec<RejectedRow>,
+    /// Number of rows holding byte sequences that were malformed in the encoding of the file
+    pub malformed_encoding: usize,
+}
+
+///
+/// A row that was skipped while reading an input file.
+///
+pub struct RejectedRow {
+    /// Line of a CSV row, or the 1-based row number in a Parquet file
+    pub line: u64,
+    pub reason: String,
+    /// The raw fields of the row (empty if the row couldn't be parsed at all or isn't CSV)
+    pub fields: ByteRecord,
+}
+
+///
+/// The cluster a record was labeled with in the saved index, and the id of the record of
+/// the base index whose cluster it joined (if any).
+///
+pub struct IndexCluster<'a> {
+    pub label: u32,
+    pub existing_id: Option<&'a str>,
+}
+
+///
+/// Reads the records of a data source of the job, then applies the rejected rows budget and
+/// the id uniqueness policy of the job.
+///
+pub async fn load_corpus(
+    client: &S3Client,
+    config: &DedupConfig,
+    source: &DataSource,
+) -> Result<Corpus, ServiceError> {
+    let start = std::time::Instant::now();
+    let inputs = list_input_files(client, source).await?;
+    let mut corpus = pull_data_files(client, inputs, config.embedding_column.as_deref()).await?;
+    corpus.check_rejected_rows(config.max_rejected_rows, config.max_rejected_percent)?;
+    corpus.duplicate_ids = corpus.enforce_unique_ids(config.duplicate_ids)?;
+    println!(
+        "{} file(s) downloaded in {:.4} secs",
+        corpus.sources.len(),
+        (std::time::Instant::now() - start).as_secs_f64()
+    );
+    Ok(corpus)
+}
+
+///
+/// Expands the data source of a job into the list of objects to read.
+///
+pub async fn list_input_files(
+    client: &S3Client,
+    source: &DataSource,
+) -> Result<Vec<DataFile>, ServiceError> {
+    let inputs = match source {
+        DataSource::File(file) => vec![file.clone()],
+        DataSource::Files(files) => files.clone(),
+        DataSource::Prefix {
+            bucket,
+            prefix,
+            dialect,
+        } => list_objects_in_s3(client, bucket.clone(), prefix.clone())
+            .await?
+            .into_iter()
+            .map(|key| DataFile {
+                bucket: bucket.clone(),
+                key,
+                dialect: dialect.clone(),
+            })
+            .collect(),
+        DataSource::Manifest {
+            bucket,
+            manifest,
+            dialect,
+        } => {
+            let bytes = download_object_from_s3(client, bucket.clone(), manifest.clone()).await?;
+            let text = String::from_utf8(bytes)
+                .map_err(|_| ServiceError::bad_request("manifest must be UTF-8 text"))?;
+            parse_manifest(bucket, &text, dialect)?
+        }
+    };
+    if inputs.is_empty() {
+        return Err(no_input_files(source));
+    }
+    Ok(inputs)
+}
+
+/// The bad request failing a job whose `data` or `reference` names no input file.
+fn no_input_files(source: &DataSource) -> ServiceError {
+    ServiceError::bad_request(match source {
+        DataSource::Prefix { bucket, prefix, .. } => {
+            format!("no input files found under 's3://{bucket}/{prefix}'")
+        }
+        DataSource::Manifest {
+            bucket, manifest, ..
+        } => format!("manifest 's3://{bucket}/{manifest}' lists no input files"),
+        _ => "no input files found".to_string(),
+    })
+}
+
+///
+/// Parses the input files listed by a manifest, one per line. Blank lines and lines starting
+/// with `#` are skipped.
+///
+fn parse_manifest(
+    bucket: &str,
+    text: &str,
+    dialect: &CsvDialect,
+) -> Result<Vec<DataFile>, ServiceError> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| parse_manifest_entry(bucket, line, dialect))
+        .collect()
+}
+
+fn parse_manifest_entry(
+    bucket: &str,
+    line: &str,
+    dialect: &CsvDialect,
+) -> Result<DataFile, ServiceError> {
+    match line.strip_prefix("s3://") {
+        Some(uri) => match uri.split_once('/') {
+            Some((bucket, key)) if !bucket.is_empty() && !key.is_empty() => Ok(DataFile {
+                bucket: bucket.to_string(),
+                key: key.to_string(),
+                dialect: dialect.clone(),
+            }),
+            _ => Err(ServiceError::bad_request(format!(
+                "invalid manifest entry '{line}'"
+            ))),
+        },
+        None => Ok(DataFile {
+            bucket: bucket.to_string(),
+            key: line.to_string(),
+            dialect: dialect.clone(),
+        }),
+    }
+}
+
+impl Corpus {
+    ///
+    /// Fails the job if more rows were rejected than the error budget allows. Without
+    /// a budget no rejected rows are tolerated.
+    ///
+    pub fn check_rejected_rows(
+        &self,
+        max_rows: Option<usize>,
+        max_percent: Option<f64>,
+    ) -> Result<(), ServiceError> {
+        let num_rejected = self.rejected.len();
+        if num_rejected == 0 {
+            return Ok(());
+        }
+        let num_rows = num_rejected + self.records.len();
+        let percent = 100.0 * num_rejected as f64 / num_rows as f64;
+        let within_budget = match (max_rows, max_percent) {
+            (None, None) => false,
+            (max_rows, max_percent) => {
+                max_rows.is_none_or(|max_rows| num_rejected <= max_rows)
+                    && max_percent.is_none_or(|max_percent| percent <= max_percent)
+            }
+        };
+        if within_budget {
+            return Ok(());
+        }
+        let (source, first) = &self.rejected[0];
+        Err(ServiceError::bad_request(format!(
+            "{num_rejected} of {num_rows} rows ({percent:.2}%) could not be read, first at line {} of 's3://{}/{}': {}",
+            first.line, self.sources[*source].bucket, self.sources[*source].key, first.reason
+        )))
+    }
+
+    ///
+    /// Applies the id uniqueness policy to the records, returning the number of ids
+    /// that were shared by more than one record.
+    ///
+    pub fn enforce_unique_ids(&mut self, policy: DuplicateIdPolicy) -> Result<usize, ServiceError> {
+        let mut occurrences: HashMap<&str, usize> = HashMap::with_capacity(self.records.len());
+        for record in &self.records {
+            *occurrences.entry(record.id.as_str()).or_default() += 1;
+        }
+        let colliding: Vec<&str> = occurrences
+            .iter()
+            .filter(|&(_, &count)| count > 1)
+            .map(|(&id, _)| id)
+            .collect();
+        let num_colliding = colliding.len();
+        if num_colliding == 0 {
+            return Ok(0);
+        }
+        match policy {
+            DuplicateIdPolicy::Reject => {
+                return Err(ServiceError::bad_request(format!(
+                    "{num_colliding} record id(s) are used more than once (e.g. '{}')",
+                    colliding[0]
+                )))
+            }
+            DuplicateIdPolicy::KeepFirst => {
+                let mut seen = HashSet::with_capacity(occurrences.len());
+                let keep: Vec<bool> = self
+                    .records
+                    .iter()
+                    .map(|record| seen.insert(record.id.as_str()))
+                    .collect();
+                self.retain(&keep);
+            }
+            DuplicateIdPolicy::KeepLast => {
+                let keep: Vec<bool> = self
+                    .records
+                    .iter()
+                    .map(|record| {
+                        let remaining = occurrences.get_mut(record.id.as_str()).unwrap();
+                        *remaining -= 1;
+                        *remaining == 0
+                    })
+                    .collect();
+                self.retain(&keep);
+            }
+            DuplicateIdPolicy::Suffix => {
+                let mut seen: HashMap<&str, usize> = HashMap::with_capacity(num_colliding);
+                let mut assigned: HashSet<String> = HashSet::new();
+                let mut renames = Vec::new();
+                for (idx, record) in self.records.iter().enumerate() {
+                    if occurrences[record.id.as_str()] == 1 {
+                        continue;
+                    }
+                    let count = seen.entry(record.id.as_str()).or_default();
+                    *count += 1;
+                    if *count == 1 {
+                        continue;
+                    }
+                    let mut suffixed = format!("{}-{count}", record.id);
+                    while occurrences.contains_key(suffixed.as_str())
+                        || assigned.contains(&suffixed)
+                    {
+                        *count += 1;
+                        suffixed = format!("{}-{count}", record.id);
+                    }
+                    assigned.insert(suffixed.clone());
+                    renames.push((idx, suffixed));
+                }
+                for (idx, id) in renames {
+                    self.records[idx].id = id;
+                }
+            }
+        }
+        Ok(num_colliding)
+    }
+
+    /// The texts of all records.
+    pub fn texts(&self) -> Vec<&str> {
+        self.records
+            .iter()
+            .map(|record| record.text.as_str())
+            .collect()
+    }
+
+    ///
+    /// Applies the id uniqueness policy to the records reusing the id of one of the `existing`
+    /// records (e.g. of a base index), which come first and can't be changed: `keepFirst`
+    /// drops the new records, `suffix` renames them and `keepLast` fails like `reject`.
+    /// Returns the number of ids used by both.
+    ///
+    pub fn enforce_unique_ids_against(
+        &mut self,
+        existing: &[String],
+        policy: DuplicateIdPolicy,
+    ) -> Result<usize, ServiceError> {
+        let existing: HashSet<&str> = existing.iter().map(String::as_str).collect();
+        let colliding: Vec<usize> = (0..self.records.len())
+            .filter(|&idx| existing.contains(self.records[idx].id.as_str()))
+            .collect();
+        let Some(&first) = colliding.first() else {
+            return Ok(0);
+        };
+        match policy {
+            DuplicateIdPolicy::Reject | DuplicateIdPolicy::KeepLast => {
+                return Err(ServiceError::bad_request(format!(
+                    "{} record id(s) are already used by the base index (e.g. '{}'), whose records can't be dropped",
+                    colliding.len(),
+                    self.records[first].id
+                )))
+            }
+            DuplicateIdPolicy::KeepFirst => {
+                let keep: Vec<bool> = self
+                    .records
+                    .iter()
+                    .map(|record| !existing.contains(record.id.as_str()))
+                    .collect();
+                self.retain(&keep);
+            }
+            DuplicateIdPolicy::Suffix => {
+                let mut assigned: HashSet<String> =
+                    self.records.iter().map(|record| record.id.clone()).collect();
+                for &idx in &colliding {
+                    let id = &self.records[idx].id;
+                    let mut count = 2;
+                    let mut suffixed = format!("{id}-{count}");
+                    while existing.contains(suffixed.as_str()) || assigned.contains(&suffixed) {
+                        count += 1;
+                        suffixed = format!("{id}-{count}");
+                    }
+                    assigned.insert(suffixed.clone());
+                    self.records[idx].id = suffixed;
+                }
+            }
+        }
+        Ok(colliding.len())
+    }
+
+    ///
+    /// The number of dimensions shared by all embeddings, failing the job if they differ or
+    /// if there are none to build random hyperplanes from.
+    ///
+    pub fn embedding_dimensions(&self) -> Result<usize, ServiceError> {
+        let Some(first) = self.embeddings.first() else {
+            return Err(ServiceError::bad_request(
+                "embeddingColumn jobs need records in data and, for join jobs, in reference",
+            ));
+        };
+        if first.is_empty() {
+            return Err(ServiceError::bad_request(format!(
+                "embedding of record '{}' has no dimensions",
+                self.records[0].id
+            )));
+        }
+        match self
+            .embeddings
+            .iter()
+            .position(|embedding| embedding.len() != first.len())
+        {
+            None => Ok(first.len()),
+            Some(idx) => Err(ServiceError::bad_request(format!(
+                "embedding of record '{}' has {} dimensions, expected {}",
+                self.records[idx].id,
+                self.embeddings[idx].len(),
+                first.len()
+            ))),
+        }
+    }
+
+    fn retain(&mut self, keep: &[bool]) {
+        let mut flags = keep.iter();
+        self.records.retain(|_| *flags.next().unwrap());
+        let mut flags = keep.iter();
+        self.record_sources.retain(|_| *flags.next().unwrap());
+        if !self.embeddings.is_empty() {
+            let mut flags = keep.iter();
+            self.embeddings.retain(|_| *flags.next().unwrap());
+        }
+    }
+}
+
+pub async fn pull_data_files(
+    client: &S3Client,
+    inputs: Vec<DataFile>,
+    embedding_column: Option<&str>,
+) -> Result<Corpus, ServiceError> {
+    let files: Vec<FileRows> = stream::iter(inputs.iter())
+        .map(|data| pull_data_file(client, data, embedding_column))
+        .buffered(MAX_CONCURRENT_DOWNLOADS)
+        .try_collect()
+        .await?;
+    let num_records = files.iter().map(|file| file.records.len()).sum();
+    let mut records = Vec::with_capacity(num_records);
+    let mut record_sources = Vec::with_capacity(num_records);
+    let mut embeddings = Vec::new();
+    let mut rejected = Vec::new();
+    let mut malformed_encoding = 0;
+    for (source, file) in files.into_iter().enumerate() {
+        malformed_encoding += file.malformed_encoding;
+        record_sources.extend(iter::repeat_n(source, file.records.len()));
+        records.extend(file.records);
+        embeddings.extend(file.embeddings);
+        rejected.extend(file.rejected.into_iter().map(|row| (source, row)));
+    }
+    Ok(Corpus {
+        num_rows: records.len() + rejected.len(),
+        records,
+        record_sources,
+        sources: inputs,
+        embeddings,
+        rejected,
+        malformed_encoding,
+        duplicate_ids: 0,
+    })
+}
+
+///
+/// Reads the records of a single CSV or Parquet (`.parquet` key) file, along with their
+/// embeddings if `embedding_column` is given. Rows that can't be turned into a record are
+/// returned separately instead of failing the whole file.
+///
 pub async fn pull_data_file(
     client: &S3Client,
     data: &DataFile,
-) -> Result<Vec<Record>, ServiceError> {
+    embedding_column: Option<&str>,
+) -> Result<FileRows, ServiceError> {
     let bytes = download_object_from_s3(client, data.bucket.clone(), data.key.clone()).await?;
-    let mut reader = Reader::from_reader(bytes.as_slice());
-    let headers = reader
-        .headers()
-        .map_err(ServiceError::internal_server_error)?
-        .clone();
-    reader
-        .records()
-        .into_iter()
-        .map(|record| match record {
-            Ok(rec) => rec.deserialize(Some(&headers)).map_err(|_| {
-                ServiceError::bad_request(String::from("file must contain columns 'id' and 'text'"))
-            }),
-            Err(err) => Err(ServiceError::internal_server_error(err)),
+    if data.key.ends_with(".parquet") {
+        return read_parquet_rows(bytes, embedding_column);
+    }
+    read_csv_rows(bytes, &data.dialect, embedding_column)
+}
+
+///
+/// Reads the records of a CSV file laid out as described by `dialect`, transcoding it to
+/// UTF-8 first. Malformed byte sequences are replaced with U+FFFD, and the rows holding them
+/// are counted.
+///
+fn read_csv_rows(
+    bytes: Vec<u8>,
+    dialect: &CsvDialect,
+    embedding_column: Option<&str>,
+) -> Result<FileRows, ServiceError> {
+    let mut builder = csv_reader_builder(dialect)?;
+    let (bytes, had_errors) = transcode_to_utf8(bytes, dialect.encoding.as_deref())?;
+    let mut reader = builder.flexible(true).from_reader(bytes.as_slice());
+    let headers = if dialect.has_headers.unwrap_or(true) {
+        let headers = reader.headers().map_err(ServiceError::bad_request)?.clone();
+        check_columns(headers.iter(), embedding_column)?;
+        Some(headers)
+    } else if embedding_column.is_some() {
+        return Err(ServiceError::bad_request(
+            "embeddingColumn requires files with a header row",
+        ));
+    } else {
+        None
+    };
+    let embedding_field = embedding_column.and_then(|column| {
+        headers
+            .as_ref()
+            .and_then(|headers| headers.iter().position(|h| h == column))
+    });
+    let num_fields = headers.as_ref().map_or(2, StringRecord::len);
+    let flexible = dialect.flexible.unwrap_or(false);
+    let mut rows = FileRows::default();
+    for row in reader.byte_records() {
+        let row = match row {
+            Ok(row) => row,
+            Err(err) if err.is_io_error() => return Err(ServiceError::internal_server_error(err)),
+            Err(err) => {
+                rows.rejected.push(RejectedRow {
+                    line: err.position().map_or(0, |pos| pos.line()),
+                    reason: err.to_string(),
+                    fields: ByteRecord::new(),
+                });
+                continue;
+            }
+        };
+        let line = row.position().map_or(0, |pos| pos.line());
+        if had_errors && row.iter().any(contains_replacement_character) {
+            rows.malformed_encoding += 1;
+        }
+        let reason = if !flexible && row.len() != num_fields {
+            format!("expected {num_fields} fields, found {}", row.len())
+        } else {
+            match StringRecord::from_byte_record(row.clone()) {
+                Err(err) => format!("invalid UTF-8: {}", err.utf8_error()),
+                Ok(fields) => match fields.deserialize::<Record>(headers.as_ref()) {
+                    Err(err) => err.to_string(),
+                    Ok(record) if record.id.is_empty() => String::from("empty id"),
+                    Ok(record) => match embedding_field
+                        .map(|idx| parse_embedding(fields.get(idx).unwrap_or_default()))
+                        .transpose()
+                    {
+                        Err(reason) => reason,
+                        Ok(embedding) => {
+                            rows.records.push(record);
+                            rows.embeddings.extend(embedding);
+                            continue;
+                        }
+                    },
+                },
+            }
+        };
+        rows.rejected.push(RejectedRow {
+            line,
+            reason,
+            fields: row,
+        });
+    }
+    Ok(rows)
+}
+
+///
+/// Fails unless the columns of a file include an `id` and a `text` column, or the embedding
+/// column for jobs comparing embeddings.
+///
+fn check_columns<'c>(
+    mut columns: impl Iterator<Item = &'c str> + Clone,
+    embedding_column: Option<&str>,
+) -> Result<(), ServiceError> {
+    let content_column = embedding_column.unwrap_or("text");
+    if !columns.clone().any(|c| c == "id") || !columns.any(|c| c == content_column) {
+        return Err(ServiceError::bad_request(format!(
+            "file must contain columns 'id' and '{content_column}'"
+        )));
+    }
+    Ok(())
+}
+
+///
+/// Parses an embedding stored as a JSON array of numbers.
+///
+fn parse_embedding(value: &str) -> Result<Vec<f32>, String> {
+    let embedding: Vec<f32> =
+        serde_json::from_str(value).map_err(|err| format!("invalid embedding: {err}"))?;
+    check_embedding(embedding)
+}
+
+fn check_embedding(embedding: Vec<f32>) -> Result<Vec<f32>, String> {
+    if embedding.is_empty() {
+        Err(String::from("empty embedding"))
+    } else if !embedding.iter().all(|x| x.is_finite()) {
+        Err(String::from("embedding contains values that aren't finite"))
+    } else {
+        Ok(embedding)
+    }
+}
+
+///
+/// Reads the records of a Parquet file with an `id` column (string or integer) and a `text`
+/// column or the embedding column (list of floats).
+///
+fn read_parquet_rows(
+    bytes: Vec<u8>,
+    embedding_column: Option<&str>,
+) -> Result<FileRows, ServiceError> {
+    let reader =
+        SerializedFileReader::new(Bytes::from(bytes)).map_err(ServiceError::bad_request)?;
+    let schema = reader.metadata().file_metadata().schema_descr_ptr();
+    check_columns(
+        schema
+            .root_schema()
+            .get_fields()
+            .iter()
+            .map(|field| field.name()),
+        embedding_column,
+    )?;
+    let mut rows = FileRows::default();
+    let row_iter = reader
+        .get_row_iter(None)
+        .map_err(ServiceError::bad_request)?;
+    for (idx, row) in row_iter.enumerate() {
+        let row = row.map_err(ServiceError::bad_request)?;
+        let mut record = Record {
+            id: String::new(),
+            text: String::new(),
+        };
+        let mut embedding = Err(String::from("missing embedding"));
+        let mut reason = None;
+        for (name, field) in row.get_column_iter() {
+            match (name.as_str(), field) {
+                ("id", Field::Str(id)) => record.id = id.clone(),
+                ("id", Field::Null) => {}
+                ("id", Field::Bytes(_) | Field::Group(_) | Field::ListInternal(_)) => {
+                    reason = Some(format!("unsupported id {field}"))
+                }
+                ("id", _) => record.id = field.to_string(),
+                ("text", Field::Str(text)) => record.text = text.clone(),
+                (name, Field::ListInternal(list)) if Some(name) == embedding_column => {
+                    embedding = list
+                        .elements()
+                        .iter()
+                        .map(|element| match element {
+                            Field::Float(x) => Ok(*x),
+                            Field::Double(x) => Ok(*x as f32),
+                            _ => Err(format!("embedding contains {element}")),
+                        })
+                        .collect::<Result<Vec<f32>, String>>()
+                        .and_then(check_embedding);
+                }
+                _ => {}
+            }
+        }
+        let reason = match (reason, embedding) {
+            (Some(reason), _) => reason,
+            (None, _) if record.id.is_empty() => String::from("empty id"),
+            (None, Err(reason)) if embedding_column.is_some() => reason,
+            (None, embedding) => {
+                rows.records.push(record);
+                rows.embeddings.extend(embedding.ok());
+                continue;
+            }
+        };
+        rows.rejected.push(RejectedRow {
+            line: idx as u64 + 1,
+            reason,
+            fields: ByteRecord::new(),
+        });
+    }
+    Ok(rows)
+}
+
+fn csv_reader_builder(dialect: &CsvDialect) -> Result<ReaderBuilder, ServiceError> {
+    fn ascii(name: &str, c: char) -> Result<u8, ServiceError> {
+        u8::try_from(c)
+            .ok()
+            .filter(u8::is_ascii)
+            .ok_or_else(|| ServiceError::bad_request(format!("{name} must be an ASCII character")))
+    }
+    let mut builder = ReaderBuilder::new();
+    builder.has_headers(dialect.has_headers.unwrap_or(true));
+    if let Some(delimiter) = dialect.delimiter {
+        builder.delimiter(ascii("delimiter", delimiter)?);
+    }
+    if let Some(quote) = dialect.quote {
+        builder.quote(ascii("quote", quote)?);
+    }
+    if let Some(escape) = dialect.escape {
+        builder.escape(Some(ascii("escape", escape)?));
+        builder.double_quote(false);
+    }
+    if let Some(comment) = dialect.comment {
+        builder.comment(Some(ascii("comment", comment)?));
+    }
+    Ok(builder)
+}
+
+///
+/// Decodes the file contents from the given encoding label to UTF-8, returning whether
+/// malformed byte sequences were replaced with U+FFFD. UTF-8 input is passed through untouched
+/// so invalid rows can be rejected individually.
+///
+fn transcode_to_utf8(
+    bytes: Vec<u8>,
+    encoding: Option<&str>,
+) -> Result<(Vec<u8>, bool), ServiceError> {
+    let encoding = match encoding {
+        Some(label) => Encoding::for_label(label.as_bytes())
+            .ok_or_else(|| ServiceError::bad_request(format!("unsupported encoding '{label}'")))?,
+        None => UTF_8,
+    };
+    if encoding == UTF_8 {
+        return Ok((bytes, false));
+    }
+    let (text, had_errors) = encoding.decode_with_bom_removal(&bytes);
+    Ok((text.into_owned().into_bytes(), had_errors))
+}
+
+/// Whether a field holds U+FFFD, which replaces malformed byte sequences when transcoding.
+fn contains_replacement_character(field: &[u8]) -> bool {
+    field
+        .windows(REPLACEMENT_CHARACTER.len())
+        .any(|bytes| bytes == REPLACEMENT_CHARACTER)
+}
+
+///
+/// Writes one row per record of every group, with groups given as indices into the corpus.
+/// `exact_matches` flags the records that have an identical copy in their group. When the
+/// job saves an index, `index_clusters` adds the index cluster of every record and the
+/// existing record it joined as two more columns (empty for records left out of the index).
+///
+pub async fn push_result_file(
+    client: &S3Client,
+    output: &DataFile,
+    corpus: &Corpus,
+    groups: Vec<Vec<u32>>,
+    exact_matches: &[bool],
+    index_clusters: Option<&[Option<IndexCluster<'_>>]>,
+) -> Result<Value, ServiceError> {
+    let sources = &source_uris(corpus);
+    let rows = groups.into_iter().enumerate().flat_map(|(idx, group)| {
+        let cluster_id = format!("{idx}-{}", group.len());
+        let singleton = group.len() == 1;
+        group.into_iter().map(move |rec_idx| {
+            let rec_idx = rec_idx as usize;
+            let match_kind = match (singleton, exact_matches[rec_idx]) {
+                (true, _) => "",
+                (false, true) => "exact",
+                (false, false) => "near",
+            };
+            let mut row = vec![
+                corpus.records[rec_idx].id.clone(),
+                cluster_id.clone(),
+                sources[corpus.record_sources[rec_idx]].clone(),
+                match_kind.to_string(),
+            ];
+            if let Some(index_clusters) = index_clusters {
+                match &index_clusters[rec_idx] {
+                    Some(cluster) => row.extend([
+                        cluster.label.to_string(),
+                        cluster.existing_id.unwrap_or_default().to_string(),
+                    ]),
+                    None => row.extend([String::new(), String::new()]),
+                }
+            }
+            row
         })
-        .collect()
+    });
+    upload_csv(client, output, None, rows).await
 }
 
-pub async fn push_result_file<'a>(
+///
+/// Writes the pairs of similar records found by a join job, as indices into `queried` and
+/// `indexed`, which map them to `corpus` and `reference`.
+///
+pub async fn push_pair_file(
     client: &S3Client,
-    bucket: String,
-    key: String,
-    dedup_table: DeduplicationTable<'a>,
+    output: &DataFile,
+    corpus: &Corpus,
+    queried: &[u32],
+    reference: &Corpus,
+    indexed: &[u32],
+    matches: &[Vec<(u32, f64)>],
 ) -> Result<Value, ServiceError> {
-    let mut writer = Writer::from_writer(vec![]);
-    let record_map = dedup_table
-        .grouped_ids()
+    let (sources, reference_sources) = (&source_uris(corpus), &source_uris(reference));
+    let rows = queried
+        .iter()
+        .zip(matches)
+        .flat_map(|(&query_idx, similar)| {
+            let query_idx = query_idx as usize;
+            similar.iter().map(move |&(doc, similarity)| {
+                let doc = indexed[doc as usize] as usize;
+                [
+                    corpus.records[query_idx].id.clone(),
+                    sources[corpus.record_sources[query_idx]].clone(),
+                    reference.records[doc].id.clone(),
+                    reference_sources[reference.record_sources[doc]].clone(),
+                    format!("{similarity:.4}"),
+                ]
+            })
+        });
+    upload_csv(client, output, None, rows).await
+}
+
+///
+/// Writes the ranked neighbors found by a neighbors job. `queries` and the neighbors are
+/// indices into `indexed`, which maps them to the corpus.
+///
+pub async fn push_neighbor_file(
+    client: &S3Client,
+    output: &DataFile,
+    corpus: &Corpus,
+    indexed: &[u32],
+    query_ids: &[&str],
+    nearest: &[Vec<(u32, f64)>],
+) -> Result<Value, ServiceError> {
+    let id = |pos: u32| corpus.records[indexed[pos as usize] as usize].id.clone();
+    let rows = query_ids
+        .iter()
+        .zip(nearest)
+        .flat_map(|(&query, neighbors)| {
+            neighbors
+                .iter()
+                .enumerate()
+                .map(move |(rank, &(neighbor, similarity))| {
+                    [
+                        query.to_string(),
+                        (rank + 1).to_string(),
+                        id(neighbor),
+                        format!("{similarity:.4}"),
+                    ]
+                })
+        });
+    upload_csv(client, output, None, rows).await
+}
+
+///
+/// Writes the result of a decontaminate job: every record with its number of `shared`
+/// n-grams when flagging, the records sharing at most `max_shared` when dropping.
+///
+pub async fn push_contamination_file(
+    client: &S3Client,
+    output: &DataFile,
+    corpus: &Corpus,
+    shared: &[usize],
+    max_shared: usize,
+    policy: ContaminationPolicy,
+) -> Result<Value, ServiceError> {
+    let records = corpus.records.iter().zip(shared).enumerate();
+    match policy {
+        ContaminationPolicy::Flag => {
+            let sources = source_uris(corpus);
+            let rows = records.map(|(idx, (record, &count))| {
+                [
+                    record.id.clone(),
+                    sources[corpus.record_sources[idx]].clone(),
+                    count.to_string(),
+                    decontaminate::is_contaminated(count, max_shared).to_string(),
+                ]
+            });
+            upload_csv(client, output, None, rows).await
+        }
+        ContaminationPolicy::Drop => {
+            let rows = records
+                .filter(|&(_, (_, &count))| !decontaminate::is_contaminated(count, max_shared))
+                .map(|(_, (record, _))| [record.id.as_str(), record.text.as_str()]);
+            upload_csv(client, output, Some(&["id", "text"]), rows).await
+        }
+    }
+}
+
+///
+/// Writes the cleaned text of every record of a segments job with what was removed from it.
+///
+pub async fn push_segment_file(
+    client: &S3Client,
+    output: &DataFile,
+    corpus: &Corpus,
+    texts: &[String],
+    stats: &[SegmentStats],
+) -> Result<Value, ServiceError> {
+    let header = ["id", "text", "units", "removed_units", "removed_bytes"];
+    let rows = corpus
+        .records
+        .iter()
+        .zip(texts)
+        .zip(stats)
+        .map(|((record, text), stats)| {
+            [
+                record.id.clone(),
+                text.clone(),
+                stats.units.to_string(),
+                stats.removed_units.to_string(),
+                stats.removed_bytes.to_string(),
+            ]
+        });
+    upload_csv(client, output, Some(&header), rows).await
+}
+
+///
+/// Writes the repeated spans found by a substrings job with the earlier occurrence of each.
+///
+pub async fn push_span_file(
+    client: &S3Client,
+    output: &DataFile,
+    corpus: &Corpus,
+    spans: &[Span],
+) -> Result<Value, ServiceError> {
+    let rows = spans.iter().map(|span| {
+        [
+            corpus.records[span.record as usize].id.clone(),
+            span.start.to_string(),
+            span.end.to_string(),
+            corpus.records[span.matched_record as usize].id.clone(),
+            span.matched_start.to_string(),
+        ]
+    });
+    upload_csv(client, output, None, rows).await
+}
+
+///
+/// Writes every record of the corpus with a replaced text.
+///
+pub async fn push_text_file(
+    client: &S3Client,
+    output: &DataFile,
+    corpus: &Corpus,
+    texts: &[String],
+) -> Result<Value, ServiceError> {
+    let rows = corpus
+        .records
+        .iter()
+        .zip(texts)
+        .map(|(record, text)| [record.id.as_str(), text.as_str()]);
+    upload_csv(client, output, Some(&["id", "text"]), rows).await
+}
+
+///
+/// Describes the largest band buckets of the index, which point at boilerplate shared by
+/// many records, along with the number of buckets over `max_bucket_size`. `ids` holds the
+/// id of every record of the LSH.
+///
+pub fn describe_buckets(lsh: &MinHashLSH, ids: &[&str], max_bucket_size: Option<usize>) -> Value {
+    let largest: Vec<Value> = lsh
+        .largest_buckets(REPORTED_BUCKETS_PER_BAND)
         .into_iter()
         .enumerate()
-        .flat_map(|(idx, group)| {
-            let cluster_id = format!("{idx}-{}", group.len());
-            group
+        .map(|(band, buckets)| {
+            let buckets: Vec<Value> = buckets
                 .into_iter()
-                .map(move |id| (id.to_string(), cluster_id.clone()))
-        });
-    for (rec_id, cluster_id) in record_map {
+                .map(|docs| {
+                    let ids: Vec<&str> = docs
+                        .iter()
+                        .take(REPORTED_BUCKET_IDS)
+                        .map(|&idx| ids[idx as usize])
+                        .collect();
+                    json!({ "size": docs.len(), "exampleIds": ids })
+                })
+                .collect();
+            json!({ "band": band, "buckets": buckets })
+        })
+        .collect();
+    json!({
+        "largestBuckets": largest,
+        "oversizedBuckets": max_bucket_size.map(|max| lsh.oversized_buckets(max)),
+    })
+}
+
+///
+/// Writes the rows rejected while reading the inputs to the sidecar object `name` next to the
+/// result file, returning its location (or `null` if nothing was rejected).
+///
+pub async fn push_rejected_rows(
+    client: &S3Client,
+    output: &DataFile,
+    corpus: &Corpus,
+    name: &str,
+) -> Result<Value, ServiceError> {
+    if corpus.rejected.is_empty() {
+        return Ok(Value::Null);
+    }
+    let sources = source_uris(corpus);
+    let rows = corpus.rejected.iter().map(|(source, row)| {
+        [
+            sources[*source].as_bytes(),
+            row.line.to_string().as_bytes(),
+            row.reason.as_bytes(),
+        ]
+        .map(<[u8]>::to_vec)
+        .into_iter()
+        .chain(row.fields.iter().map(<[u8]>::to_vec))
+    });
+    let header = ["source", "line", "reason", "fields"];
+    upload_csv(client, &sidecar_file(output, name), Some(&header), rows).await
+}
+
+///
+/// Writes the ids of the records that were too short to be hashed to a sidecar object next
+/// to the result file, returning its location (or `null` if there were none). Their token
+/// count leaves out `pruned` tokens.
+///
+pub async fn push_short_records(
+    client: &S3Client,
+    output: &DataFile,
+    corpus: &Corpus,
+    pruned: &PrunedTokens<'_>,
+    short: &[u32],
+) -> Result<Value, ServiceError> {
+    if short.is_empty() {
+        return Ok(Value::Null);
+    }
+    let sources = source_uris(corpus);
+    let rows = short.iter().map(|&idx| {
+        let record = &corpus.records[idx as usize];
+        [
+            record.id.clone(),
+            sources[corpus.record_sources[idx as usize]].clone(),
+            pruned.tokens(&record.text).count().to_string(),
+        ]
+    });
+    let header = ["id", "source", "tokens"];
+    upload_csv(client, &sidecar_file(output, "short"), Some(&header), rows).await
+}
+
+///
+/// Uploads `rows` as a CSV object to `target`, after a `header` row if given, returning its
+/// location. Rows may have different numbers of fields.
+///
+async fn upload_csv(
+    client: &S3Client,
+    target: &DataFile,
+    header: Option<&[&str]>,
+    rows: impl IntoIterator<Item = impl IntoIterator<Item = impl AsRef<[u8]>>>,
+) -> Result<Value, ServiceError> {
+    let mut writer = WriterBuilder::new().flexible(true).from_writer(vec![]);
+    if let Some(header) = header {
+        writer
+            .write_record(header)
+            .map_err(ServiceError::internal_server_error)?;
+    }
+    for row in rows {
         writer
-            .write_record(&[rec_id, cluster_id])
+            .write_record(row)
             .map_err(ServiceError::internal_server_error)?;
     }
     let object = writer
         .into_inner()
         .map_err(ServiceError::internal_server_error)?;
-    let output_key = key.replace("input/", "output/");
-    upload_object_to_s3(client, object, bucket.clone(), output_key.clone()).await?;
-    Ok(json!({ "bucket": bucket, "key": output_key }))
+    upload_object_to_s3(client, object, target.bucket.clone(), target.key.clone()).await?;
+    Ok(json!({ "bucket": target.bucket, "key": target.key }))
+}
+
+/// The `s3://` URI of every input file of the corpus.
+fn source_uris(corpus: &Corpus) -> Vec<String> {
+    corpus
+        .sources
+        .iter()
+        .map(|data| format!("s3://{}/{}", data.bucket, data.key))
+        .collect()
+}
+
+///
+/// Location of an additional report written alongside the result file,
+/// e.g. `output/data.csv` -> `output/data.{name}.csv`.
+///
+pub fn sidecar_file(output: &DataFile, name: &str) -> DataFile {
+    let stem = output.key.strip_suffix(".csv").unwrap_or(&output.key);
+    DataFile {
+        bucket: output.bucket.clone(),
+        key: format!("{stem}.{name}.csv"),
+        ..Default::default()
+    }
+}
+
+///
+/// Determines where the result file of a job is written.
+///
+/// An explicit `output` has its key template rendered, otherwise the `input/` directory
+/// of a single input key is swapped for `output/`. Fails with a bad request if no location
+/// can be derived or if the result or one of its sidecar objects would overwrite one of the
+/// input objects.
+///
+pub fn resolve_output_file(
+    config: &DedupConfig,
+    inputs: &[DataFile],
+) -> Result<DataFile, ServiceError> {
+    let output = match (&config.output, &config.data) {
+        (Some(output), _) => DataFile {
+            bucket: output.bucket.clone(),
+            key: render_key_template(&output.key, config)?,
+            ..Default::default()
+        },
+        (None, DataSource::File(input)) => DataFile {
+            bucket: input.bucket.clone(),
+            key: default_output_key(&input.key)?,
+            ..Default::default()
+        },
+        (None, _) => {
+            return Err(ServiceError::bad_request(
+                "an explicit 'output' is required when reading multiple input files",
+            ))
+        }
+    };
+    if output.key.is_empty() {
+        return Err(ServiceError::bad_request("output key must not be empty"));
+    }
+    let sidecars = SIDECAR_NAMES.map(|name| sidecar_file(&output, name));
+    for target in iter::once(&output).chain(&sidecars) {
+        if inputs.iter().any(|input| input.same_object(target)) {
+            return Err(ServiceError::bad_request(format!(
+                "output 's3://{}/{}' would overwrite an input file",
+                target.bucket, target.key
+            )));
+        }
+    }
+    Ok(output)
+}
+
+///
+/// Renders the key of the index snapshot to write, if the job saves one. Fails with a bad
+/// request if the snapshot would overwrite one of the input objects, the `output` result
+/// file or one of its sidecar objects.
+///
+pub fn resolve_index_file(
+    config: &DedupConfig,
+    inputs: &[DataFile],
+    output: &DataFile,
+) -> Result<Option<DataFile>, ServiceError> {
+    let Some(target) = &config.save_index else {
+        return Ok(None);
+    };
+    let target = DataFile {
+        bucket: target.bucket.clone(),
+        key: render_key_template(&target.key, config)?,
+        ..Default::default()
+    };
+    if target.key.is_empty() {
+        return Err(ServiceError::bad_request("saveIndex key must not be empty"));
+    }
+    if inputs.iter().any(|input| input.same_object(&target)) {
+        return Err(ServiceError::bad_request(format!(
+            "index snapshot 's3://{}/{}' would overwrite an input file",
+            target.bucket, target.key
+        )));
+    }
+    let sidecars = SIDECAR_NAMES.map(|name| sidecar_file(output, name));
+    if iter::once(output)
+        .chain(&sidecars)
+        .any(|file| file.same_object(&target))
+    {
+        return Err(ServiceError::bad_request(format!(
+            "index snapshot 's3://{}/{}' would overwrite the result file or one of its sidecar objects",
+            target.bucket, target.key
+        )));
+    }
+    Ok(Some(target))
+}
+
+fn default_output_key(input_key: &str) -> Result<String, ServiceError> {
+    let mut segments: Vec<&str> = input_key.split('/').collect();
+    let dirs = segments.len() - 1;
+    match segments[..dirs].iter().position(|&segment| segment == "input") {
+        Some(idx) => {
+            segments[idx] = "output";
+            Ok(segments.join("/"))
+        }
+        None => Err(ServiceError::bad_request(format!(
+            "key '{input_key}' has no 'input/' directory to derive an output key from, provide an explicit 'output'"
+        ))),
+    }
+}
+
+fn render_key_template(template: &str, config: &DedupConfig) -> Result<String, ServiceError> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(ServiceError::internal_server_error)?
+        .as_secs();
+    let unset = |name: &str| {
+        ServiceError::bad_request(format!(
+            "placeholder '{{{name}}}' in output key '{template}' needs {name} to be set"
+        ))
+    };
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(open) = rest.find('{') {
+        rendered.push_str(&rest[..open]);
+        let len = rest[open..].find('}').ok_or_else(|| {
+            ServiceError::bad_request(format!("unclosed placeholder in output key '{template}'"))
+        })?;
+        let value = match &rest[open + 1..open + len] {
+            "taskId" => config.task_id.to_string(),
+            "timestamp" => timestamp.to_string(),
+            "numPerm" => config.num_perm.ok_or_else(|| unset("numPerm"))?.to_string(),
+            "numBands" => config
+                .num_bands
+                .ok_or_else(|| unset("numBands"))?
+                .to_string(),
+            "threshold" => config
+                .threshold
+                .ok_or_else(|| unset("threshold"))?
+                .to_string(),
+            name => {
+                return Err(ServiceError::bad_request(format!(
+                    "unknown placeholder '{{{name}}}' in output key '{template}'"
+                )))
+            }
+        };
+        rendered.push_str(&value);
+        rest = &rest[open + len + 1..];
+    }
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lsh_dedup_service::response::Status;
+
+    fn config(value: Value) -> DedupConfig {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn file(key: &str) -> DataFile {
+        DataFile {
+            bucket: "bucket".to_string(),
+            key: key.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// A dedup job over `input/a.csv` and `input/b.csv` writing to `output`
+    fn job(output: &str) -> DedupConfig {
+        config(json!({
+            "taskId": 7,
+            "data": [
+                { "bucket": "bucket", "key": "input/a.csv" },
+                { "bucket": "bucket", "key": "input/b.csv" },
+            ],
+            "output": { "bucket": "bucket", "key": output },
+            "numPerm": 64,
+            "numBands": 16,
+            "threshold": 0.5,
+        }))
+    }
+
+    /// A segments job over `input/a.csv`, without LSH options
+    fn segments_job() -> DedupConfig {
+        config(json!({
+            "taskId": 7,
+            "mode": "segments",
+            "data": { "bucket": "bucket", "key": "input/a.csv" },
+        }))
+    }
+
+    /// A corpus of `num_records` records read from `input/a.csv` and `num_rejected` rejected rows
+    fn corpus(num_records: usize, num_rejected: usize) -> Corpus {
+        Corpus {
+            records: (0..num_records)
+                .map(|idx| Record {
+                    id: idx.to_string(),
+                    text: "text".to_string(),
+                })
+                .collect(),
+            record_sources: vec![0; num_records],
+            sources: vec![file("input/a.csv")],
+            embeddings: Vec::new(),
+            rejected: (0..num_rejected)
+                .map(|idx| {
+                    let row = RejectedRow {
+                        line: idx as u64 + 2,
+                        reason: "empty id".to_string(),
+                        fields: ByteRecord::new(),
+                    };
+                    (0, row)
+                })
+                .collect(),
+            num_rows: num_records + num_rejected,
+            malformed_encoding: 0,
+            duplicate_ids: 0,
+        }
+    }
+
+    #[test]
+    fn rejected_rows_need_a_budget() {
+        assert!(corpus(10, 0).check_rejected_rows(None, None).is_ok());
+        let err = corpus(9, 1).check_rejected_rows(None, None).unwrap_err();
+        assert!(matches!(err.status, Status::BadRequest));
+        assert!(err.msg.starts_with("1 of 10 rows (10.00%)"), "{}", err.msg);
+        assert!(err
+            .msg
+            .contains("line 2 of 's3://bucket/input/a.csv': empty id"));
+    }
+
+    #[test]
+    fn rejected_rows_within_budget() {
+        let corpus = corpus(96, 4);
+        assert!(corpus.check_rejected_rows(Some(4), None).is_ok());
+        assert!(corpus.check_rejected_rows(Some(3), None).is_err());
+        assert!(corpus.check_rejected_rows(None, Some(4.0)).is_ok());
+        assert!(corpus.check_rejected_rows(None, Some(3.9)).is_err());
+        assert!(corpus.check_rejected_rows(None, Some(0.0)).is_err());
+        // Both limits must hold.
+        assert!(corpus.check_rejected_rows(Some(10), Some(5.0)).is_ok());
+        assert!(corpus.check_rejected_rows(Some(3), Some(5.0)).is_err());
+        assert!(corpus.check_rejected_rows(Some(10), Some(3.0)).is_err());
+    }
+
+    #[test]
+    fn max_rejected_percent_is_a_percentage() {
+        let mut config = segments_job();
+        for percent in [0.0, 0.5, 100.0] {
+            config.max_rejected_percent = Some(percent);
+            assert!(config.validate().is_ok(), "{percent}");
+        }
+        for percent in [-0.1, 100.5, f64::NAN, f64::INFINITY] {
+            config.max_rejected_percent = Some(percent);
+            assert!(config.validate().is_err(), "{percent}");
+        }
+    }
+
+    #[test]
+    fn min_tokens_is_at_least_one() {
+        let mut config = job("output/result.csv");
+        config.min_tokens = Some(1);
+        assert!(config.validate().is_ok());
+        config.min_tokens = Some(0);
+        let err = config.validate().unwrap_err();
+        assert!(matches!(err.status, Status::BadRequest));
+    }
+
+    #[test]
+    fn max_bucket_size_is_at_least_one() {
+        let mut config = job("output/result.csv");
+        config.max_bucket_size = Some(1);
+        assert!(config.validate().is_ok());
+        config.max_bucket_size = Some(0);
+        let err = config.validate().unwrap_err();
+        assert!(matches!(err.status, Status::BadRequest));
+    }
+
+    fn read(bytes: &[u8], dialect: Value) -> Result<FileRows, ServiceError> {
+        let dialect: CsvDialect = serde_json::from_value(dialect).unwrap();
+        read_csv_rows(bytes.to_vec(), &dialect, None)
+    }
+
+    fn texts(rows: &FileRows) -> Vec<(&str, &str)> {
+        rows.records
+            .iter()
+            .map(|record| (record.id.as_str(), record.text.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn csv_dialect_options() {
+        let rows = read(b"id,text\n1,hello\n2,\"a, \"\"b\"\"\"\n", json!({})).unwrap();
+        assert_eq!(texts(&rows), [("1", "hello"), ("2", "a, \"b\"")]);
+        let dialect = json!({ "delimiter": "\t", "quote": "'", "hasHeaders": false });
+        let rows = read(b"1\t'a\tb'\n2\tc\n", dialect).unwrap();
+        assert_eq!(texts(&rows), [("1", "a\tb"), ("2", "c")]);
+        let rows = read(
+            b"id,text\n1,\"say \\\"hi\\\"\"\n",
+            json!({ "escape": "\\" }),
+        )
+        .unwrap();
+        assert_eq!(texts(&rows), [("1", "say \"hi\"")]);
+        let rows = read(b"id,text\n# 0,skipped\n1,kept\n", json!({ "comment": "#" })).unwrap();
+        assert_eq!(texts(&rows), [("1", "kept")]);
+        assert!(read(b"id,text\n", json!({ "delimiter": "\u{a7}" })).is_err());
+    }
+
+    #[test]
+    fn flexible_rows() {
+        let bytes = b"id,text\n1,a,extra\n2,b\n";
+        let rows = read(bytes, json!({})).unwrap();
+        assert_eq!(texts(&rows), [("2", "b")]);
+        assert_eq!(rows.rejected[0].line, 2);
+        assert_eq!(rows.rejected[0].reason, "expected 2 fields, found 3");
+        let rows = read(bytes, json!({ "flexible": true })).unwrap();
+        assert_eq!(texts(&rows), [("1", "a"), ("2", "b")]);
+    }
+
+    #[test]
+    fn encodings() {
+        let rows = read(b"id,text\n1,caf\xe9\n", json!({ "encoding": "latin1" })).unwrap();
+        assert_eq!(texts(&rows), [("1", "caf\u{e9}")]);
+        assert_eq!(rows.malformed_encoding, 0);
+        // 0x82 starts a two-byte Shift_JIS sequence, which the line break cuts short.
+        let bytes = b"id,text\n1,ok\n2,bad\x82\n3,\x82\xa0\n";
+        let rows = read(bytes, json!({ "encoding": "shift_jis" })).unwrap();
+        assert_eq!(
+            texts(&rows),
+            [("1", "ok"), ("2", "bad\u{fffd}"), ("3", "\u{3042}")]
+        );
+        assert_eq!(rows.malformed_encoding, 1);
+        // Without an encoding, invalid UTF-8 rows are rejected instead of replaced.
+        let rows = read(b"id,text\n1,caf\xe9\n2,ok\n", json!({})).unwrap();
+        assert_eq!(texts(&rows), [("2", "ok")]);
+        assert!(rows.rejected[0].reason.starts_with("invalid UTF-8"));
+        assert_eq!(rows.malformed_encoding, 0);
+        assert!(read(b"id,text\n", json!({ "encoding": "klingon" })).is_err());
+    }
+
+    #[test]
+    fn manifest_entries() {
+        let dialect = CsvDialect {
+            delimiter: Some(';'),
+            ..Default::default()
+        };
+        let manifest = "input/a.csv\n\n  # shards of another bucket\n s3://other/input/b.csv \r\n\t\ns3://other/nested/dir/c.csv\n";
+        let files = parse_manifest("bucket", manifest, &dialect).unwrap();
+        let locations: Vec<(&str, &str)> = files
+            .iter()
+            .map(|file| (file.bucket.as_str(), file.key.as_str()))
+            .collect();
+        assert_eq!(
+            locations,
+            [
+                ("bucket", "input/a.csv"),
+                ("other", "input/b.csv"),
+                ("other", "nested/dir/c.csv"),
+            ]
+        );
+        assert!(files.iter().all(|file| file.dialect.delimiter == Some(';')));
+        assert!(parse_manifest("bucket", "\n# nothing\n  \n", &dialect)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn invalid_manifest_entries() {
+        let dialect = CsvDialect::default();
+        for line in ["s3://", "s3://bucket", "s3://bucket/", "s3:///key.csv"] {
+            let err = parse_manifest_entry("bucket", line, &dialect).unwrap_err();
+            assert!(matches!(err.status, Status::BadRequest), "{line}");
+        }
+    }
+
+    #[test]
+    fn empty_listings_are_bad_requests() {
+        let prefix: DataSource = serde_json::from_value(json!({
+            "bucket": "bucket",
+            "prefix": "input/day/",
+        }))
+        .unwrap();
+        let manifest: DataSource = serde_json::from_value(json!({
+            "bucket": "bucket",
+            "manifest": "manifests/day.txt",
+        }))
+        .unwrap();
+        for source in [prefix, manifest, DataSource::Files(Vec::new())] {
+            assert!(matches!(no_input_files(&source).status, Status::BadRequest));
+        }
+    }
+
+    #[test]
+    fn key_template_placeholders() {
+        let config = job("");
+        assert_eq!(
+            render_key_template("out/{taskId}-{numPerm}-{numBands}-{threshold}.csv", &config)
+                .unwrap(),
+            "out/7-64-16-0.5.csv"
+        );
+        let rendered = render_key_template("out/{timestamp}.csv", &config).unwrap();
+        let timestamp = &rendered["out/".len()..rendered.len() - ".csv".len()];
+        assert!(timestamp.parse::<u64>().unwrap() > 0);
+        assert_eq!(
+            render_key_template("out/plain.csv", &config).unwrap(),
+            "out/plain.csv"
+        );
+    }
+
+    #[test]
+    fn key_template_rejects_unknown_placeholders() {
+        let config = job("");
+        assert!(render_key_template("out/{task}.csv", &config).is_err());
+        assert!(render_key_template("out/{}.csv", &config).is_err());
+        assert!(render_key_template("out/{taskId.csv", &config).is_err());
+        let config = segments_job();
+        assert!(render_key_template("out/{numPerm}.csv", &config).is_err());
+        assert!(render_key_template("out/{threshold}.csv", &config).is_err());
+        assert_eq!(
+            render_key_template("out/{taskId}.csv", &config).unwrap(),
+            "out/7.csv"
+        );
+    }
+
+    #[test]
+    fn output_defaults_next_to_a_single_input() {
+        let output = resolve_output_file(&segments_job(), &[file("input/a.csv")]).unwrap();
+        assert_eq!(output.key, "output/a.csv");
+        assert!(resolve_output_file(&job(""), &[file("input/a.csv")]).is_err());
+    }
+
+    #[test]
+    fn output_colliding_with_an_input_is_rejected() {
+        let inputs = [file("input/a.csv"), file("input/b.csv")];
+        assert!(resolve_output_file(&job("input/b.csv"), &inputs).is_err());
+        assert!(resolve_output_file(&job("input/{taskId}.csv"), &[file("input/7.csv")]).is_err());
+        let output = resolve_output_file(&job("output/{taskId}.csv"), &inputs).unwrap();
+        assert_eq!(output.key, "output/7.csv");
+        // The rejected rows would be written to `input/c.rejected.csv`, the spans of a
+        // substrings job to `input/c.spans.csv`.
+        for sidecar in ["input/c.rejected.csv", "input/c.spans.csv"] {
+            let inputs = [file("input/a.csv"), file(sidecar)];
+            assert!(resolve_output_file(&job("input/c.csv"), &inputs).is_err());
+            assert!(resolve_output_file(&job("input/d.csv"), &inputs).is_ok());
+        }
+    }
+
+    #[test]
+    fn index_colliding_with_an_output_is_rejected() {
+        let inputs = [file("input/a.csv")];
+        let output = file("output/result.csv");
+        let saving = |key: &str| {
+            let mut config = job("output/result.csv");
+            config.save_index = Some(file(key));
+            config
+        };
+        for key in [
+            "input/a.csv",
+            "output/result.csv",
+            "output/result.rejected.csv",
+            "output/result.short.csv",
+        ] {
+            let err = resolve_index_file(&saving(key), &inputs, &output).unwrap_err();
+            assert!(matches!(err.status, Status::BadRequest), "{key}");
+        }
+        let index = resolve_index_file(&saving("indexes/{taskId}.lshidx"), &inputs, &output)
+            .unwrap()
+            .unwrap();
+        assert_eq!(index.key, "indexes/7.lshidx");
+        assert!(
+            resolve_index_file(&job("output/result.csv"), &inputs, &output)
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    /// A corpus of records with the given ids, read from a single file
+    fn with_ids(ids: &[&str]) -> Corpus {
+        Corpus {
+            records: ids
+                .iter()
+                .enumerate()
+                .map(|(row, id)| Record {
+                    id: id.to_string(),
+                    text: format!("row {row}"),
+                })
+                .collect(),
+            record_sources: vec![0; ids.len()],
+            sources: vec![file("input/a.csv")],
+            embeddings: Vec::new(),
+            rejected: Vec::new(),
+            num_rows: ids.len(),
+            malformed_encoding: 0,
+            duplicate_ids: 0,
+        }
+    }
+
+    fn ids(corpus: &Corpus) -> Vec<&str> {
+        corpus
+            .records
+            .iter()
+            .map(|record| record.id.as_str())
+            .collect()
+    }
+
+    fn row_texts(corpus: &Corpus) -> Vec<&str> {
+        corpus
+            .records
+            .iter()
+            .map(|record| record.text.as_str())
+            .collect()
+    }
+
+    /// Ids of six rows, two ids of which are repeated
+    const REPEATED: [&str; 6] = ["a", "b", "a", "c", "a", "b"];
+
+    #[test]
+    fn repeated_ids_are_rejected() {
+        let err = with_ids(&REPEATED)
+            .enforce_unique_ids(DuplicateIdPolicy::Reject)
+            .unwrap_err();
+        assert!(matches!(err.status, Status::BadRequest));
+        assert!(err.msg.starts_with("2 record id(s)"), "{}", err.msg);
+
+        let mut unique = with_ids(&["a", "b", "c"]);
+        assert_eq!(
+            unique
+                .enforce_unique_ids(DuplicateIdPolicy::Reject)
+                .unwrap(),
+            0
+        );
+        assert_eq!(ids(&unique), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn keep_first_keeps_the_first_row_of_every_id() {
+        let mut corpus = with_ids(&REPEATED);
+        // Two ids are shared, by five rows.
+        assert_eq!(
+            corpus
+                .enforce_unique_ids(DuplicateIdPolicy::KeepFirst)
+                .unwrap(),
+            2
+        );
+        assert_eq!(ids(&corpus), ["a", "b", "c"]);
+        assert_eq!(row_texts(&corpus), ["row 0", "row 1", "row 3"]);
+        assert_eq!(corpus.record_sources.len(), 3);
+    }
+
+    #[test]
+    fn keep_last_keeps_the_last_row_of_every_id_in_place() {
+        let mut corpus = with_ids(&REPEATED);
+        assert_eq!(
+            corpus
+                .enforce_unique_ids(DuplicateIdPolicy::KeepLast)
+                .unwrap(),
+            2
+        );
+        assert_eq!(ids(&corpus), ["c", "a", "b"]);
+        assert_eq!(row_texts(&corpus), ["row 3", "row 4", "row 5"]);
+        assert_eq!(corpus.record_sources.len(), 3);
+    }
+
+    #[test]
+    fn suffix_renames_repeated_ids() {
+        let mut corpus = with_ids(&REPEATED);
+        assert_eq!(
+            corpus
+                .enforce_unique_ids(DuplicateIdPolicy::Suffix)
+                .unwrap(),
+            2
+        );
+        assert_eq!(ids(&corpus), ["a", "b", "a-2", "c", "a-3", "b-2"]);
+        assert_eq!(row_texts(&corpus), row_texts(&with_ids(&REPEATED)));
+    }
+
+    #[test]
+    fn suffix_skips_ids_used_by_the_input() {
+        let mut corpus = with_ids(&["a", "a-2", "a", "a-3", "a"]);
+        assert_eq!(
+            corpus
+                .enforce_unique_ids(DuplicateIdPolicy::Suffix)
+                .unwrap(),
+            1
+        );
+        assert_eq!(ids(&corpus), ["a", "a-2", "a-4", "a-3", "a-5"]);
+    }
+
+    #[test]
+    fn unique_ids_against_base_index() {
+        let indexed: Vec<String> = ["a", "b", "b-2"].iter().map(|id| id.to_string()).collect();
+
+        let mut fresh = with_ids(&["c", "d"]);
+        assert_eq!(
+            fresh
+                .enforce_unique_ids_against(&indexed, DuplicateIdPolicy::Reject)
+                .unwrap(),
+            0
+        );
+        assert_eq!(ids(&fresh), ["c", "d"]);
+
+        for policy in [DuplicateIdPolicy::Reject, DuplicateIdPolicy::KeepLast] {
+            let err = with_ids(&["a", "c"])
+                .enforce_unique_ids_against(&indexed, policy)
+                .unwrap_err();
+            assert!(matches!(err.status, Status::BadRequest));
+            assert!(err.msg.contains("'a'"), "{}", err.msg);
+        }
+
+        let mut kept = with_ids(&["a", "c", "b"]);
+        assert_eq!(
+            kept.enforce_unique_ids_against(&indexed, DuplicateIdPolicy::KeepFirst)
+                .unwrap(),
+            2
+        );
+        assert_eq!(ids(&kept), ["c"]);
+        assert_eq!(kept.record_sources.len(), 1);
+
+        let mut suffixed = with_ids(&["b", "b-3", "a"]);
+        assert_eq!(
+            suffixed
+                .enforce_unique_ids_against(&indexed, DuplicateIdPolicy::Suffix)
+                .unwrap(),
+            2
+        );
+        assert_eq!(ids(&suffixed), ["b-4", "b-3", "a-2"]);
+    }
 }
@@ -1,24 +1,530 @@
+use crate::error::ServiceError;
 use serde::{Deserialize, Serialize};
 
-#[derive(Deserialize, Serialize)]
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct DataFile {
     pub bucket: String,
     pub key: String,
+    #[serde(flatten)]
+    pub dialect: CsvDialect,
+}
+
+/// How the input CSV files are laid out.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CsvDialect {
+    /// Field delimiter (defaults to `,`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delimiter: Option<char>,
+    /// Quote character (defaults to `"`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quote: Option<char>,
+    /// Escape character for quotes inside quoted fields (defaults to doubling the quote)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub escape: Option<char>,
+    /// Whether the first row holds the column names (defaults to `true`). Without
+    /// headers the first two columns are read as `id` and `text`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub has_headers: Option<bool>,
+    /// Lines starting with this character are ignored
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<char>,
+    /// Allow rows with a different number of fields than the header
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flexible: Option<bool>,
+    /// Character encoding label (e.g. `latin1`, `windows-1252`, `utf-16le`), defaults to UTF-8
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<String>,
+}
+
+impl DataFile {
+    /// Whether both refer to the same object, regardless of how it is read.
+    pub fn same_object(&self, other: &DataFile) -> bool {
+        self.bucket == other.bucket && self.key == other.key
+    }
+}
+
+/// The input objects of a job, all of which are deduplicated together.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum DataSource {
+    File(DataFile),
+    Files(Vec<DataFile>),
+    /// Every object stored under `prefix`
+    Prefix {
+        bucket: String,
+        prefix: String,
+        #[serde(flatten)]
+        dialect: CsvDialect,
+    },
+    /// A text object listing one `s3://bucket/key` URI (or key within `bucket`) per line
+    Manifest {
+        bucket: String,
+        manifest: String,
+        #[serde(flatten)]
+        dialect: CsvDialect,
+    },
+}
+
+/// How the similarity of two records is measured by dedup jobs.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum SimilarityMeasure {
+    /// Jaccard similarity of their token sets
+    #[default]
+    Jaccard,
+    /// Share of the distinct tokens of the smaller record found in the larger one
+    Containment,
+}
+
+/// What a job computes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum JobMode {
+    /// Cluster the records of `data` into groups of near-duplicates
+    #[default]
+    Dedup,
+    /// Pair every record of `data` with its near-duplicates in `reference`, without
+    /// clustering either dataset
+    Join,
+    /// Flag or drop the records of `data` sharing long n-grams with `reference`
+    Decontaminate,
+    /// List the `top_k` most similar records of every queried record of `data`, whatever
+    /// their similarity
+    Neighbors,
+    /// Remove the lines or paragraphs of `data` repeated within or across records
+    Segments,
+    /// Find the long byte spans of `data` repeating earlier text, and report or excise them
+    Substrings,
+}
+
+impl JobMode {
+    /// Whether jobs of this mode sign the records with an LSH family, which needs `numPerm`
+    /// and `numBands`.
+    pub fn builds_index(self) -> bool {
+        matches!(self, JobMode::Dedup | JobMode::Join | JobMode::Neighbors)
+    }
+}
+
+/// The units segments jobs split the text of every record into.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum SegmentUnit {
+    /// Every line
+    #[default]
+    Line,
+    /// Runs of non-blank lines, separated by blank lines
+    Paragraph,
+}
+
+/// What substrings jobs do with the repeated spans they find.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum SpanPolicy {
+    /// List every repeated span with its offsets
+    #[default]
+    Report,
+    /// Write every record with its repeated spans removed as a new input file
+    Excise,
+}
+
+/// What decontaminate jobs do with contaminated records.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ContaminationPolicy {
+    /// List every record with its number of shared n-grams
+    #[default]
+    Flag,
+    /// Write the records that aren't contaminated as a new input file
+    Drop,
+}
+
+/// How to handle records that share an id with another record of the job.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum DuplicateIdPolicy {
+    /// Fail the job with a bad request
+    #[default]
+    Reject,
+    /// Keep the first record read for each id
+    KeepFirst,
+    /// Keep the last record read for each id
+    KeepLast,
+    /// Keep every record, renaming repeated ids to `{id}-2`, `{id}-3`, ...
+    Suffix,
+}
+
+/// How record signatures are computed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum SignatureScheme {
+    /// Classic MinHash with one hash permutation per signature value
+    #[default]
+    MinHash,
+    /// One-permutation hashing with optimal densification, a single hash per token
+    OnePermutation,
+    /// 64-bit SimHash fingerprints compared by hamming distance
+    SimHash64,
+    /// 128-bit SimHash fingerprints compared by hamming distance
+    SimHash128,
+}
+
+impl SignatureScheme {
+    /// Width of the SimHash fingerprints, `None` for MinHash schemes.
+    pub fn simhash_bits(self) -> Option<usize> {
+        match self {
+            SignatureScheme::SimHash64 => Some(64),
+            SignatureScheme::SimHash128 => Some(128),
+            SignatureScheme::MinHash | SignatureScheme::OnePermutation => None,
+        }
+    }
+}
+
+/// What to do with band buckets holding more records than `maxBucketSize`.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum BucketPolicy {
+    /// Don't use the bucket to find candidates
+    #[default]
+    Skip,
+    /// Compare its records against a deterministic pseudo-random sample of `maxBucketSize` of
+    /// them: the records with the lowest hash of their index
+    Sample,
+    /// Put all of its records into one group without comparing them
+    Group,
+}
+
+/// How much every distinct token of a record counts towards the similarity of two records.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum TokenWeighting {
+    /// Every distinct token counts the same (plain jaccard similarity)
+    #[default]
+    None,
+    /// Tokens are weighted by how often they occur in the record
+    Tf,
+    /// Term frequency scaled by the inverse document frequency over the input
+    TfIdf,
+}
+
+/// What to do with records that have fewer than `minTokens` tokens.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ShortTextPolicy {
+    /// Leave them out of the result file
+    #[default]
+    Exclude,
+    /// Group those with the same tokens, ignoring whitespace differences
+    ExactTokens,
+    /// Group those with exactly the same text
+    ExactString,
 }
 
 #[derive(Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct DedupConfig {
     pub task_id: usize,
-    pub data: DataFile,
-    pub num_perm: usize,
-    pub num_bands: usize,
-    pub threshold: f64,
+    #[serde(default)]
+    pub mode: JobMode,
+    pub data: DataSource,
+    /// Dataset indexed by join jobs and queried with the records of `data`, or the benchmark
+    /// records of decontaminate jobs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reference: Option<DataSource>,
+    /// Number of tokens of the n-grams compared by decontaminate jobs (defaults to 13)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ngram_size: Option<usize>,
+    /// Records sharing more distinct n-grams with the reference are contaminated (defaults
+    /// to 0, i.e. any shared n-gram)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_shared_ngrams: Option<usize>,
+    #[serde(default)]
+    pub contamination_policy: ContaminationPolicy,
+    /// Units segments jobs split records into (defaults to lines)
+    #[serde(default)]
+    pub segment_unit: SegmentUnit,
+    /// Shortest repeated span, in bytes, found by substrings jobs (defaults to 100)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_span_bytes: Option<usize>,
+    #[serde(default)]
+    pub span_policy: SpanPolicy,
+    /// Number of nearest neighbors listed by neighbors jobs (defaults to 10)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<usize>,
+    /// Ids of the records whose neighbors are listed (defaults to every record)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub query_ids: Option<Vec<String>>,
+    /// Texts whose neighbors among the records are listed instead (neighbors jobs)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub queries: Option<Vec<Record>>,
+    /// Where to write the result file. The key may contain `{taskId}`, `{timestamp}`,
+    /// `{numPerm}`, `{numBands}` and `{threshold}` placeholders.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output: Option<DataFile>,
+    /// Where to write a snapshot of the index at the end of the job (optional). The key may
+    /// contain the same placeholders as the output key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub save_index: Option<DataFile>,
+    /// Index snapshot of earlier jobs to dedupe the records against (optional). The records
+    /// are signed with the parameters stored in the snapshot, and the index extended by them
+    /// is written to `save_index`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base_index: Option<DataFile>,
+    /// Required by the modes building an index (dedup, join and neighbors)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub num_perm: Option<usize>,
+    /// Required by the modes building an index (dedup, join and neighbors)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub num_bands: Option<usize>,
+    /// Required by dedup and join jobs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub threshold: Option<f64>,
+    #[serde(default)]
+    pub signature_scheme: SignatureScheme,
+    #[serde(default)]
+    pub similarity: SimilarityMeasure,
+    /// Number of set size partitions of the LSH Ensemble indexing containment jobs (defaults
+    /// to 16)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub num_partitions: Option<usize>,
+    /// Column holding a precomputed embedding per record. Records are then compared by the
+    /// cosine similarity of their embeddings (random-hyperplane LSH with `num_perm` planes)
+    /// instead of their text.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub embedding_column: Option<String>,
+    /// Tokens occurring in a larger share of the records are left out of the signatures
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_document_frequency: Option<f64>,
+    /// Weighted MinHash (weighted jaccard similarity) when not `None`
+    #[serde(default)]
+    pub token_weighting: TokenWeighting,
+    /// Number of low bits of every signature value kept for thresholding (b-bit MinHash),
+    /// defaults to all 32
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature_bits: Option<u32>,
+    /// Records with fewer whitespace separated tokens are handled according to
+    /// `short_text_policy` instead of being hashed (defaults to 1, i.e. empty texts)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_tokens: Option<usize>,
+    #[serde(default)]
+    pub short_text_policy: ShortTextPolicy,
+    /// Band buckets with more records are handled according to `bucket_policy`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_bucket_size: Option<usize>,
+    #[serde(default)]
+    pub bucket_policy: BucketPolicy,
+    #[serde(default)]
+    pub duplicate_ids: DuplicateIdPolicy,
+    /// Number of unreadable rows to skip before failing the job
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_rejected_rows: Option<usize>,
+    /// Percentage of unreadable rows to skip before failing the job
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_rejected_percent: Option<f64>,
+}
+
+impl DedupConfig {
+    ///
+    /// The number of permutations and bands of the LSH of jobs building an index, which
+    /// require them.
+    ///
+    pub fn lsh_params(&self) -> Result<(usize, usize), ServiceError> {
+        match (self.num_perm, self.num_bands) {
+            (Some(num_perm), Some(num_bands)) => Ok((num_perm, num_bands)),
+            _ => Err(ServiceError::bad_request(
+                "numPerm and numBands are required by dedup, join and neighbors jobs",
+            )),
+        }
+    }
+
+    /// The similarity threshold of dedup and join jobs, which require it.
+    pub fn threshold(&self) -> Result<f64, ServiceError> {
+        self.threshold.ok_or_else(|| {
+            ServiceError::bad_request("threshold is required by dedup and join jobs")
+        })
+    }
+
+    /// Rejects parameters the job can't be run with.
+    pub fn validate(&self) -> Result<(), ServiceError> {
+        match self.mode {
+            JobMode::Dedup | JobMode::Neighbors | JobMode::Segments | JobMode::Substrings
+                if self.reference.is_some() =>
+            {
+                return Err(ServiceError::bad_request(
+                    "reference only applies to join and decontaminate jobs",
+                ));
+            }
+            JobMode::Join | JobMode::Decontaminate if self.reference.is_none() => {
+                return Err(ServiceError::bad_request(
+                    "join and decontaminate jobs require a reference",
+                ));
+            }
+            JobMode::Join
+            | JobMode::Decontaminate
+            | JobMode::Neighbors
+            | JobMode::Segments
+            | JobMode::Substrings
+                if self.save_index.is_some() || self.base_index.is_some() =>
+            {
+                return Err(ServiceError::bad_request(
+                    "saveIndex and baseIndex only apply to dedup jobs",
+                ));
+            }
+            JobMode::Join if matches!(self.bucket_policy, BucketPolicy::Group) => {
+                return Err(ServiceError::bad_request(
+                    "the group bucketPolicy doesn't apply to join jobs",
+                ));
+            }
+            JobMode::Decontaminate | JobMode::Segments | JobMode::Substrings
+                if self.embedding_column.is_some() =>
+            {
+                return Err(ServiceError::bad_request(
+                    "embeddingColumn doesn't apply to decontaminate, segments and substrings jobs",
+                ));
+            }
+            JobMode::Decontaminate if self.ngram_size == Some(0) => {
+                return Err(ServiceError::bad_request("ngramSize must be at least 1"));
+            }
+            JobMode::Neighbors
+                if self.embedding_column.is_some()
+                    || self.signature_scheme.simhash_bits().is_some()
+                    || self.signature_bits.is_some() =>
+            {
+                return Err(ServiceError::bad_request(
+                    "neighbors jobs need full MinHash signatures of the texts: minHash or onePermutation signatureScheme, no signatureBits or embeddingColumn",
+                ));
+            }
+            JobMode::Neighbors if self.top_k == Some(0) => {
+                return Err(ServiceError::bad_request("topK must be at least 1"));
+            }
+            JobMode::Neighbors if self.queries.is_some() && self.query_ids.is_some() => {
+                return Err(ServiceError::bad_request(
+                    "queries and queryIds can't be combined",
+                ));
+            }
+            JobMode::Substrings if self.min_span_bytes == Some(0) => {
+                return Err(ServiceError::bad_request("minSpanBytes must be at least 1"));
+            }
+            _ => {}
+        }
+        if self.similarity == SimilarityMeasure::Containment {
+            if self.mode != JobMode::Dedup || self.save_index.is_some() || self.base_index.is_some()
+            {
+                return Err(ServiceError::bad_request(
+                    "containment similarity only applies to dedup jobs without saveIndex or baseIndex",
+                ));
+            }
+            if self.embedding_column.is_some()
+                || self.signature_scheme.simhash_bits().is_some()
+                || self.signature_bits.is_some()
+                || self.token_weighting != TokenWeighting::None
+            {
+                return Err(ServiceError::bad_request(
+                    "containment similarity needs full unweighted MinHash signatures of the texts: minHash or onePermutation signatureScheme, no signatureBits, tokenWeighting or embeddingColumn",
+                ));
+            }
+            if self.num_partitions == Some(0) {
+                return Err(ServiceError::bad_request(
+                    "numPartitions must be at least 1",
+                ));
+            }
+        }
+        if self
+            .max_rejected_percent
+            .is_some_and(|percent| !(0.0..=100.0).contains(&percent))
+        {
+            return Err(ServiceError::bad_request(
+                "maxRejectedPercent must be between 0 and 100",
+            ));
+        }
+        if self.min_tokens == Some(0) {
+            // Empty texts would be hashed and collide in every band.
+            return Err(ServiceError::bad_request("minTokens must be at least 1"));
+        }
+        if self.max_bucket_size == Some(0) {
+            // An empty sample would silently disable deduplication.
+            return Err(ServiceError::bad_request(
+                "maxBucketSize must be at least 1",
+            ));
+        }
+        if self.base_index.is_some() && self.save_index.is_none() {
+            return Err(ServiceError::bad_request(
+                "baseIndex requires saveIndex to write the updated index to",
+            ));
+        }
+        if self.mode.builds_index() {
+            self.validate_lsh()?;
+        }
+        Ok(())
+    }
+
+    /// Rejects parameters the LSH of jobs building an index can't be built with.
+    fn validate_lsh(&self) -> Result<(), ServiceError> {
+        let (num_perm, num_bands) = self.lsh_params()?;
+        match self.signature_scheme.simhash_bits() {
+            Some(bits) if num_bands == 0 || num_bands > bits => {
+                return Err(ServiceError::bad_request(format!(
+                    "numBands must be between 1 and {bits} for SimHash"
+                )));
+            }
+            Some(_) if self.signature_bits.is_some() => {
+                return Err(ServiceError::bad_request(
+                    "signatureBits only applies to MinHash signatures",
+                ));
+            }
+            None if num_bands == 0 || num_bands > num_perm => {
+                return Err(ServiceError::bad_request(
+                    "numBands must be between 1 and numPerm",
+                ));
+            }
+            _ => {}
+        }
+        if self.embedding_column.is_some()
+            && (self.signature_scheme != SignatureScheme::MinHash || self.signature_bits.is_some())
+        {
+            return Err(ServiceError::bad_request(
+                "signatureScheme and signatureBits don't apply to embeddingColumn jobs",
+            ));
+        }
+        if matches!(self.mode, JobMode::Dedup | JobMode::Join)
+            && !(0.0..=1.0).contains(&self.threshold()?)
+        {
+            return Err(ServiceError::bad_request(
+                "threshold must be between 0 and 1",
+            ));
+        }
+        if self
+            .max_document_frequency
+            .is_some_and(|ratio| !(ratio > 0.0 && ratio <= 1.0))
+        {
+            return Err(ServiceError::bad_request(
+                "maxDocumentFrequency must be greater than 0 and at most 1",
+            ));
+        }
+        if self.token_weighting != TokenWeighting::None
+            && self.signature_scheme == SignatureScheme::OnePermutation
+        {
+            return Err(ServiceError::bad_request(
+                "tokenWeighting can't be combined with the onePermutation signatureScheme",
+            ));
+        }
+        if self
+            .signature_bits
+            .is_some_and(|bits| !(1..=32).contains(&bits))
+        {
+            return Err(ServiceError::bad_request(
+                "signatureBits must be between 1 and 32",
+            ));
+        }
+        Ok(())
+    }
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Record {
     pub id: String,
+    /// Not required when records are compared by their embeddings
+    #[serde(default)]
     pub text: String,
 }
 
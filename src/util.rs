@@ -2,7 +2,7 @@ use crate::error::ServiceError;
 use crate::response::Status;
 use futures::stream::TryStreamExt;
 use rusoto_core::Region;
-use rusoto_s3::{GetObjectRequest, PutObjectRequest, S3Client, S3};
+use rusoto_s3::{GetObjectRequest, ListObjectsV2Request, PutObjectRequest, S3Client, S3};
 use std::env;
 use std::str::FromStr;
 
@@ -68,3 +68,36 @@ pub async fn upload_object_to_s3(
         .map(|_| ())
         .map_err(ServiceError::internal_server_error)
 }
+
+pub async fn list_objects_in_s3(
+    client: &S3Client,
+    bucket: String,
+    prefix: String,
+) -> Result<Vec<String>, ServiceError> {
+    let mut keys = Vec::new();
+    let mut continuation_token = None;
+    loop {
+        let request = ListObjectsV2Request {
+            bucket: bucket.clone(),
+            prefix: Some(prefix.clone()),
+            continuation_token,
+            ..Default::default()
+        };
+        let output = client
+            .list_objects_v2(request)
+            .await
+            .map_err(ServiceError::internal_server_error)?;
+        keys.extend(
+            output
+                .contents
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|object| object.key)
+                .filter(|key| !key.ends_with('/')),
+        );
+        match output.next_continuation_token {
+            Some(token) if output.is_truncated.unwrap_or(false) => continuation_token = Some(token),
+            _ => return Ok(keys),
+        }
+    }
+}